@@ -0,0 +1,3319 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+use std::time::Duration;
+use std::io::{self, BufRead, BufReader, Lines, Write};
+use std::iter;
+use std::ops::RangeInclusive;
+use std::fmt::Formatter;
+
+// Per-instruction debug output, sent to the trace writer or, when the VM is verbose, stdout.
+// The line is only formatted when something will receive it.
+macro_rules! trace {
+    ($vm:expr, $($arg:tt)*) => {
+        if $vm.verbose || $vm.trace_writer.is_some() {
+            let line = format!($($arg)*);
+            $vm.emit_trace(&line);
+        }
+    };
+}
+
+#[derive(Clone, Copy)]
+pub struct Instruction {
+    pub opcode: i32,
+    pub steps_next: usize,
+}
+
+pub const I_ADD: Instruction = Instruction { opcode: 1, steps_next: 4 };
+pub const I_MUL: Instruction = Instruction { opcode: 2, steps_next: 4 };
+pub const I_IN: Instruction = Instruction { opcode: 3, steps_next: 2 };
+pub const I_OUT: Instruction = Instruction { opcode: 4, steps_next: 2 };
+pub const I_JT: Instruction = Instruction { opcode: 5, steps_next: 3 };
+pub const I_JF: Instruction = Instruction { opcode: 6, steps_next: 3 };
+pub const I_LT: Instruction = Instruction { opcode: 7, steps_next: 4 };
+pub const I_EQ: Instruction = Instruction { opcode: 8, steps_next: 4 };
+pub const I_ADJ: Instruction = Instruction { opcode: 9, steps_next: 2 };
+pub const I_HALT: Instruction = Instruction { opcode: 99, steps_next: 0 };
+
+// Memory grows on demand up to this many words.
+pub const MAX_MEMORY: usize = 1 << 20;
+
+pub const MODE_REF: i32 = 0;
+pub const MODE_VAL: i32 = 1;
+pub const MODE_REL: i32 = 2;
+
+#[derive(Debug, PartialEq)]
+pub enum VmError {
+    TruncatedInstruction { ip: usize, opcode: i32 },
+    UnknownOpcode(i32),
+    PhaseOutOfRange(i64),
+    DuplicatePhase(i64),
+    JumpOutOfRange(usize),
+    InvalidMode(i64),
+    BadMode(i32),
+    NegativeAddress(i64),
+    AddressOutOfRange(i64),
+    ArithmeticOverflow { opcode: i32, a: i64, b: i64 },
+    StepLimitExceeded,
+    JumpLimitExceeded,
+    InputCursorOutOfRange(usize),
+    NegativeOpcode { addr: usize, word: i64 },
+    HaltedOnError,
+    InputExhausted,
+    OutputChannelClosed,
+    InfiniteLoop { ip: usize },
+    MissingOutput,
+    NonAsciiInput(char),
+    Context { error: Box<VmError>, context: String },
+}
+
+impl VmError {
+    pub fn root_cause(&self) -> &VmError {
+        match self {
+            VmError::Context { error, .. } => error.root_cause(),
+            error => error,
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::TruncatedInstruction { ip, opcode } =>
+                write!(f, "truncated instruction with opcode {} at ip={}", opcode, ip),
+            VmError::UnknownOpcode(opcode) => write!(f, "unknown opcode {}", opcode),
+            VmError::PhaseOutOfRange(phase) => write!(f, "phase setting {} out of range", phase),
+            VmError::DuplicatePhase(phase) => write!(f, "phase setting {} used more than once", phase),
+            VmError::JumpOutOfRange(addr) => write!(f, "jump target {} outside the program", addr),
+            VmError::InvalidMode(instruction) => write!(f, "invalid parameter modes in {}", instruction),
+            VmError::BadMode(mode) => write!(f, "unknown parameter mode {}", mode),
+            VmError::NegativeAddress(addr) => write!(f, "negative memory address {}", addr),
+            VmError::AddressOutOfRange(addr) => write!(f, "memory address {} out of range", addr),
+            VmError::ArithmeticOverflow { opcode, a, b } =>
+                write!(f, "opcode {} overflowed on operands {} and {}", opcode, a, b),
+            VmError::StepLimitExceeded => write!(f, "step limit exceeded"),
+            VmError::JumpLimitExceeded => write!(f, "jump limit exceeded"),
+            VmError::InputCursorOutOfRange(pos) => write!(f, "input cursor {} past the end of the inputs", pos),
+            VmError::NegativeOpcode { addr, word } => write!(f, "negative opcode {} at address {}", word, addr),
+            VmError::HaltedOnError => write!(f, "VM halted because of an error"),
+            VmError::InputExhausted => write!(f, "ran out of input"),
+            VmError::OutputChannelClosed => write!(f, "output channel closed"),
+            VmError::InfiniteLoop { ip } => write!(f, "infinite loop detected at ip={}", ip),
+            VmError::MissingOutput => write!(f, "no output left to read"),
+            VmError::NonAsciiInput(c) => write!(f, "non-ASCII character {:?} in ASCII input", c),
+            VmError::Context { error, context } => write!(f, "{} ({})", error, context),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Event<T = i64> {
+    Read { addr: i64, value: T },
+    Write { addr: i64, value: T },
+    Input(T),
+    Output(T),
+    Jump(i64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExecRecord<T = i64> {
+    pub ip: usize,
+    pub opcode: i32,
+    pub modes: [i32; 3],
+    pub events: Vec<Event<T>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WriteRecord<T = i64> {
+    pub ip: usize,
+    pub addr: usize,
+    pub old: T,
+    pub new: T,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum HaltReason {
+    Halt,
+    Error,
+}
+
+// Why run_until_output stopped.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputEvent<T = i64> {
+    Output(T),
+    NeedsInput,
+    Halted,
+}
+
+// Why run stopped.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunOutcome {
+    Halted,
+    NeedsInput,
+    StepLimit,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScalarState {
+    pub ip: usize,
+    pub in_p: usize,
+    pub out_p: usize,
+    pub out_rp: usize,
+    pub halted: bool,
+    pub halt_reason: Option<HaltReason>,
+    pub waiting_for_input: bool,
+    pub relative_base: i64,
+    pub jumps: usize,
+    pub steps: u64,
+}
+
+// Matches the disassembler mnemonics so traces and listings agree.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match instruction_for(self.opcode) {
+            Some(_) => write!(f, "{}", self.mnemonic()),
+            None => write!(f, "UNKNOWN({})", self.opcode),
+        }
+    }
+}
+
+impl Instruction {
+    pub fn mnemonic(&self) -> &'static str {
+        match self.opcode {
+            1 => "ADD",
+            2 => "MUL",
+            3 => "IN",
+            4 => "OUT",
+            5 => "JT",
+            6 => "JF",
+            7 => "LT",
+            8 => "EQ",
+            9 => "ADJ",
+            99 => "HALT",
+            _ => "DATA",
+        }
+    }
+
+    // The parameter the instruction writes to, which may not be in immediate mode.
+    pub fn dest_param(&self) -> Option<usize> {
+        match self.opcode {
+            1 | 2 | 7 | 8 => Some(3),
+            3 => Some(1),
+            _ => None,
+        }
+    }
+}
+
+pub fn instruction_for(opcode: i32) -> Option<Instruction> {
+    match opcode {
+        1 => Some(I_ADD),
+        2 => Some(I_MUL),
+        3 => Some(I_IN),
+        4 => Some(I_OUT),
+        5 => Some(I_JT),
+        6 => Some(I_JF),
+        7 => Some(I_LT),
+        8 => Some(I_EQ),
+        9 => Some(I_ADJ),
+        99 => Some(I_HALT),
+        _ => None
+    }
+}
+
+// Walks the program from address 0 the way the VM would if it never jumped,
+// stopping after a HALT, at an unknown opcode or at a truncated instruction.
+pub fn decode_linear(program: &[i64]) -> Vec<(usize, Instruction, ParaModes)> {
+    let mut decoded = vec!();
+    let mut ip = 0;
+    while ip < program.len() {
+        let instr = match instruction_for((program[ip] % 100) as i32) {
+            Some(instr) => instr,
+            None => break,
+        };
+        if ip + instr.steps_next > program.len() {
+            break;
+        }
+        let halt = instr.opcode == I_HALT.opcode;
+        let next = ip + instr.steps_next;
+        decoded.push((ip, instr, ParaModes::new(program[ip])));
+        if halt {
+            break;
+        }
+        ip = next;
+    }
+    decoded
+}
+
+// The address decode_linear stopped at: the HALT itself, or the first word it could not decode.
+pub fn decode_end(decoded: &[(usize, Instruction, ParaModes)]) -> usize {
+    decoded.last().map_or(0, |(ip, instr, _)| ip + instr.steps_next)
+}
+
+pub fn static_instruction_count(program: &[i64]) -> usize {
+    decode_linear(program).len()
+}
+
+pub fn static_opcode_counts(program: &[i64]) -> HashMap<i32, usize> {
+    let mut counts = HashMap::new();
+    for (_, instr, _) in decode_linear(program) {
+        *counts.entry(instr.opcode).or_insert(0) += 1;
+    }
+    counts
+}
+
+pub fn uses_relative_mode(program: &[i64]) -> bool {
+    decode_linear(program).iter().any(|(_, instr, modes)| {
+        instr.opcode == I_ADJ.opcode || (1..instr.steps_next).any(|n| modes.mode(n as i32) == MODE_REL)
+    })
+}
+
+// A hint only: jumps computed at runtime can order input and output differently.
+pub fn is_interactive(program: &[i64]) -> bool {
+    let mut seen_output = false;
+    for (_, instr, _) in decode_linear(program) {
+        if instr.opcode == I_OUT.opcode {
+            seen_output = true;
+        } else if instr.opcode == I_IN.opcode && seen_output {
+            return true;
+        }
+    }
+    false
+}
+
+// Only immediate targets count; a target read from memory is only known at runtime.
+pub fn jump_targets(program: &[i64]) -> HashSet<usize> {
+    decode_linear(program).iter()
+        .filter(|(_, instr, modes)| {
+            (instr.opcode == I_JT.opcode || instr.opcode == I_JF.opcode) && modes.mode(2) == MODE_VAL
+        })
+        .map(|(ip, _, _)| program[ip + 2])
+        .filter(|&dest| dest >= 0)
+        .map(|dest| dest as usize)
+        .collect()
+}
+
+pub fn format_operand(arg: i64, mode: i32) -> String {
+    match mode {
+        MODE_VAL => format!("#{}", arg),
+        MODE_REL => format!("~{}", arg),
+        _ => format!("@{}", arg),
+    }
+}
+
+// Walks the program from address 0 yielding (address, length, text) for each instruction.
+// Words that do not decode are listed one at a time as DATA.
+fn disassemble_walk(program: &[i64]) -> Vec<(usize, usize, String)> {
+    let mut entries = vec!();
+    let mut addr = 0;
+    while addr < program.len() {
+        let word = program[addr];
+        let (len, text) = match instruction_for((word % 100) as i32) {
+            Some(instr) if addr + instr.steps_next <= program.len() => {
+                let len = instr.steps_next.max(1);
+                (len, format_instruction(instr, &program[addr..addr + len]))
+            }
+            _ => (1, format!("DATA {}", word)),
+        };
+        entries.push((addr, len, text));
+        addr += len;
+    }
+    entries
+}
+
+// words starts with the instruction word itself and holds all of its operands.
+fn format_instruction(instr: Instruction, words: &[i64]) -> String {
+    let modes = ParaModes::new(words[0]);
+    let mut text = instr.to_string();
+    for (n, &arg) in words.iter().enumerate().skip(1) {
+        text.push(' ');
+        text.push_str(&format_operand(arg, modes.mode(n as i32)));
+    }
+    text
+}
+
+// One line per instruction, e.g. "    0: MUL @4 #3 @4".
+pub fn disassemble(program: &[i64]) -> String {
+    disassemble_walk(program).iter()
+        .map(|(addr, _, text)| format!("{:>5}: {}", addr, text))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// One line per instruction: address, the raw words it occupies and the decoded form.
+pub fn disassemble_verbose(program: &[i64]) -> Vec<String> {
+    disassemble_walk(program).into_iter()
+        .map(|(addr, len, text)| {
+            let raw = format!("{:?}", &program[addr..addr + len]);
+            format!("{:>5}: {:<24} {}", addr, raw, text)
+        })
+        .collect()
+}
+
+// (address, old, new) for every word that differs. Words past the end of the
+// shorter memory count as zero, as they read in a VM that grows its memory.
+pub fn mem_diff(before: &[i64], after: &[i64]) -> Vec<(usize, i64, i64)> {
+    (0..before.len().max(after.len()))
+        .map(|addr| (addr, before.get(addr).copied().unwrap_or(0), after.get(addr).copied().unwrap_or(0)))
+        .filter(|(_, old, new)| old != new)
+        .collect()
+}
+
+#[derive(Default)]
+pub struct ValidateOptions {
+    pub reject_negative_opcodes: bool,
+}
+
+pub fn validate(program: &[i64]) -> Result<(), VmError> {
+    validate_with(program, &ValidateOptions::default())
+}
+
+// A best-effort lint, not a guarantee: walks the program from address 0 like decode_linear,
+// checking each instruction the way the VM would before executing it. Data that follows
+// straight-line code can be misread as instructions, and code only reached by jumps is not seen.
+// The first problem found is returned with its address as context.
+pub fn validate_with(program: &[i64], options: &ValidateOptions) -> Result<(), VmError> {
+    let mut addr = 0;
+    while addr < program.len() {
+        let instr = check_instruction(program, addr, options).map_err(|error| VmError::Context {
+            error: Box::new(error),
+            context: format!("at address {}", addr),
+        })?;
+        if instr.opcode == I_HALT.opcode {
+            break;
+        }
+        addr += instr.steps_next;
+    }
+    Ok(())
+}
+
+fn check_instruction(program: &[i64], addr: usize, options: &ValidateOptions) -> Result<Instruction, VmError> {
+    let word = program[addr];
+    if options.reject_negative_opcodes && word < 0 {
+        return Err(VmError::NegativeOpcode { addr, word });
+    }
+    let opcode = (word % 100) as i32;
+    let instr = instruction_for(opcode).ok_or(VmError::UnknownOpcode(opcode))?;
+    if addr + instr.steps_next > program.len() {
+        return Err(VmError::TruncatedInstruction { ip: addr, opcode });
+    }
+    let modes = ParaModes::new(word);
+    for n in 1..instr.steps_next {
+        let param = Param::new(program[addr + n], modes.mode(n as i32));
+        if param.is_reference() && param.value < 0 {
+            return Err(VmError::NegativeAddress(param.value));
+        }
+        if !param.is_valid() || (param.is_value() && instr.dest_param() == Some(n)) {
+            return Err(VmError::BadMode(param.mode));
+        }
+    }
+    Ok(instr)
+}
+
+#[derive(Debug)]
+pub enum ParseProgramError {
+    Io(io::Error),
+    InvalidToken { line: usize, position: usize, token: String },
+    Empty,
+}
+
+impl From<io::Error> for ParseProgramError {
+    fn from(error: io::Error) -> Self {
+        ParseProgramError::Io(error)
+    }
+}
+
+impl fmt::Display for ParseProgramError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseProgramError::Io(error) => write!(f, "failed to read program: {}", error),
+            ParseProgramError::InvalidToken { line, position, token } =>
+                write!(f, "invalid token {:?} at line {}, position {}", token, line, position),
+            ParseProgramError::Empty => write!(f, "no program found in the input"),
+        }
+    }
+}
+
+// The word type a VM computes with. Addresses, parameter modes and the relative
+// base stay i64 whatever the word type is.
+pub trait Cell: Copy + Default + PartialEq + PartialOrd + From<u8> + fmt::Display + fmt::Debug + Send + 'static {
+    fn checked_add(self, other: Self) -> Option<Self>;
+    fn checked_mul(self, other: Self) -> Option<Self>;
+    fn to_i64(self) -> i64;
+}
+
+macro_rules! impl_cell {
+    ($($t:ty),*) => {
+        $(impl Cell for $t {
+            fn checked_add(self, other: $t) -> Option<$t> {
+                <$t>::checked_add(self, other)
+            }
+
+            fn checked_mul(self, other: $t) -> Option<$t> {
+                <$t>::checked_mul(self, other)
+            }
+
+            fn to_i64(self) -> i64 {
+                self as i64
+            }
+        })*
+    };
+}
+
+impl_cell!(i32, i64);
+
+pub struct Param {
+    pub value: i64,
+    pub mode: i32,
+}
+
+impl Param {
+    pub fn new(value: i64, mode: i32) -> Param {
+        Param {
+            value,
+            mode,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        if !(self.mode == MODE_REF || self.mode == MODE_VAL || self.mode == MODE_REL) { return false; }
+        if self.mode == MODE_REF && self.value < 0 { return false; }
+        true
+    }
+
+    pub fn is_reference(&self) -> bool {
+        self.mode == MODE_REF
+    }
+
+    pub fn is_value(&self) -> bool {
+        self.mode == MODE_VAL
+    }
+
+    pub fn is_relative(&self) -> bool {
+        self.mode == MODE_REL
+    }
+}
+
+#[derive(Clone)]
+pub struct ParaModes {
+    pub modes: [i32; 3]
+}
+
+impl ParaModes {
+    fn param_modes(instr: i64) -> [i32; 3] {
+        let mut params: [i32; 3] = [0; 3];
+        let param_part = (instr - instr % 100) / 100;
+        params[0] = (param_part % 10) as i32;
+        params[1] = (((param_part - param_part % 10) / 10) % 10) as i32;
+        params[2] = (((param_part - (param_part % 100)) / 100) % 10) as i32;
+//        println!("MODES: instr={} : {} => {},{},{}", instr, param_part, params[0], params[1], params[2]);
+        params
+    }
+
+    pub fn new(instr: i64) -> ParaModes {
+        ParaModes {
+            modes: ParaModes::param_modes(instr)
+        }
+    }
+    pub fn mode(&self, n: i32) -> i32 {
+        match n {
+            1 => self.modes[0],
+            2 => self.modes[1],
+            3 => self.modes[2],
+            _ => panic!("Unsupported parameter mode number")
+        }
+    }
+}
+
+impl fmt::Display for ParaModes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Modes({} {} {})", self.modes[0], self.modes[1], self.modes[2])
+    }
+}
+
+impl<T: Cell> fmt::Display for VM<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "VM(ip={} input=", self.ip)?;
+        for (inp_ind, value) in self.inputs.iter().enumerate() {
+            if inp_ind == self.in_p {
+                write!(f, "[{}] ", value)?;
+            } else {
+                write!(f, "{} ", value)?;
+            }
+        }
+        write!(f, "[")?;
+        if self.halted {
+            write!(f, "H")?;
+        }
+        if self.waiting_for_input {
+            write!(f, "W")?;
+        }
+        write!(f, "]")?;
+        write!(f, " output=")?;
+        for value in self.outputs.iter() {
+            write!(f, "{} ", value)?;
+        }
+        write!(f, " program=")?;
+        for value in self.program.iter() {
+            write!(f, "{} ", value)?;
+        }
+        write!(f, ")")
+    }
+}
+
+// With the serde feature, closures, channels and the trace writer are not saved.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VM<T = i64> {
+    program: Vec<T>,
+    pristine: Vec<T>,
+    ip: usize,
+    in_p: usize,
+    out_p: usize,
+    out_rp: usize,
+    halted: bool,
+    halt_reason: Option<HaltReason>,
+    waiting_for_input: bool,
+    relative_base: i64,
+    inputs: Vec<T>,
+    outputs: Vec<T>,
+    aliases: HashMap<i32, i32>,
+    step_delay: Option<Duration>,
+    log_exec: bool,
+    exec_log: Vec<ExecRecord<T>>,
+    strict_halt: bool,
+    max_jumps: Option<usize>,
+    max_steps: Option<u64>,
+    loop_limit: Option<usize>,
+    loop_visits: HashMap<usize, usize>,
+    jumps: usize,
+    steps: u64,
+    stats: HashMap<i32, u64>,
+    breakpoints: HashSet<usize>,
+    stopped_at: Option<usize>,
+    track_history: bool,
+    history: Vec<WriteRecord<T>>,
+    redo_history: Vec<WriteRecord<T>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    output_transform: Option<Box<dyn FnMut(T) -> T + Send>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    input_callback: Option<Box<dyn FnMut() -> T + Send>>,
+    taint: Option<Vec<bool>>,
+    output_taint: Vec<bool>,
+    grow_memory: bool,
+    mem_high_water: Option<usize>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    output_channel: Option<SyncSender<T>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    input_channel: Option<Receiver<T>>,
+    verbose: bool,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    trace_writer: Option<Box<dyn Write + Send>>,
+}
+
+pub type Vm32 = VM<i32>;
+pub type Vm64 = VM<i64>;
+
+// The output transform, input callback, trace writer and input channel cannot be cloned,
+// so clones start without them.
+impl<T: Cell> Clone for VM<T> {
+    fn clone(&self) -> VM<T> {
+        VM {
+            program: self.program.clone(),
+            pristine: self.pristine.clone(),
+            ip: self.ip,
+            in_p: self.in_p,
+            out_p: self.out_p,
+            out_rp: self.out_rp,
+            halted: self.halted,
+            halt_reason: self.halt_reason.clone(),
+            waiting_for_input: self.waiting_for_input,
+            relative_base: self.relative_base,
+            inputs: self.inputs.clone(),
+            outputs: self.outputs.clone(),
+            aliases: self.aliases.clone(),
+            step_delay: self.step_delay,
+            log_exec: self.log_exec,
+            exec_log: self.exec_log.clone(),
+            strict_halt: self.strict_halt,
+            max_jumps: self.max_jumps,
+            max_steps: self.max_steps,
+            loop_limit: self.loop_limit,
+            loop_visits: self.loop_visits.clone(),
+            jumps: self.jumps,
+            steps: self.steps,
+            stats: self.stats.clone(),
+            breakpoints: self.breakpoints.clone(),
+            stopped_at: self.stopped_at,
+            track_history: self.track_history,
+            history: self.history.clone(),
+            redo_history: self.redo_history.clone(),
+            output_transform: None,
+            input_callback: None,
+            taint: self.taint.clone(),
+            output_taint: self.output_taint.clone(),
+            grow_memory: self.grow_memory,
+            mem_high_water: self.mem_high_water,
+            output_channel: self.output_channel.clone(),
+            input_channel: None,
+            verbose: self.verbose,
+            trace_writer: None,
+        }
+    }
+}
+
+// Constructors are for i64 words only, so integer literals in a program do not
+// fall back to i32. Other word types are built with from_words or VmBuilder.
+impl VM {
+    pub fn new(program: Vec<i64>, inputs: Vec<i64>) -> VM {
+        VmBuilder::new().program(program).inputs(inputs).build()
+    }
+
+    pub fn from_slice(program: &[i64], inputs: &[i64]) -> VM {
+        VM::new(program.to_vec(), inputs.to_vec())
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn load_state(state: &str) -> Result<VM, serde_json::Error> {
+        serde_json::from_str(state)
+    }
+}
+
+impl<T: Cell> VM<T> {
+    pub fn from_words(program: Vec<T>, inputs: Vec<T>) -> VM<T> {
+        VmBuilder::new().program(program).inputs(inputs).build()
+    }
+
+    // Trace lines go here instead of stdout; write errors are ignored.
+    pub fn with_trace_writer(mut self, writer: Box<dyn Write + Send>) -> VM<T> {
+        self.trace_writer = Some(writer);
+        self
+    }
+
+    fn emit_trace(&mut self, line: &str) {
+        match self.trace_writer.as_mut() {
+            Some(writer) => {
+                let _ = writeln!(writer, "{}", line);
+            }
+            None => println!("{}", line),
+        }
+    }
+
+    pub fn with_verbose(mut self, verbose: bool) -> VM<T> {
+        self.verbose = verbose;
+        self
+    }
+
+    // When the queued inputs run out, block on the channel for the next one.
+    // Once every sender is gone the VM pauses for input as usual.
+    pub fn with_input_channel(mut self, receiver: Receiver<T>) -> VM<T> {
+        self.input_channel = Some(receiver);
+        self
+    }
+
+    // Outputs are sent on the channel instead of being buffered in `outputs`;
+    // a full channel blocks the VM until the reader catches up.
+    pub fn with_output_channel(mut self, sender: SyncSender<T>) -> VM<T> {
+        self.output_channel = Some(sender);
+        self
+    }
+
+    pub fn with_memory_growth(mut self, enabled: bool) -> VM<T> {
+        self.grow_memory = enabled;
+        self
+    }
+
+    // Records the access in the high-water mark and, when growth is enabled,
+    // zero-extends memory so that addr is valid.
+    fn touch_mem(&mut self, addr: usize) -> Result<(), VmError> {
+        self.mem_high_water = Some(self.mem_high_water.map_or(addr, |high| high.max(addr)));
+        if addr >= self.program.len() {
+            if !self.grow_memory || addr >= MAX_MEMORY {
+                return Err(VmError::AddressOutOfRange(addr as i64));
+            }
+            self.program.resize(addr + 1, T::from(0));
+            if let Some(taint) = self.taint.as_mut() {
+                taint.resize(addr + 1, false);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn with_taint_tracking(mut self, enabled: bool) -> VM<T> {
+        self.taint = if enabled { Some(vec![false; self.program.len()]) } else { None };
+        self
+    }
+
+    pub fn tainted_outputs(&self) -> Vec<bool> {
+        self.output_taint.clone()
+    }
+
+    // Whether parameter n of the current instruction carries a value derived from input.
+    fn param_taint(&self, n: usize, mode: i32) -> bool {
+        let taint = match &self.taint {
+            Some(taint) => taint,
+            None => return false,
+        };
+        let addr = if mode == MODE_VAL {
+            self.ip + n
+        } else {
+            match self.dest_addr(n, mode) {
+                Ok(addr) => addr as usize,
+                Err(_) => return false,
+            }
+        };
+        taint.get(addr).copied().unwrap_or(false)
+    }
+
+    fn set_taint(&mut self, addr: i64, tainted: bool) {
+        if let Some(taint) = self.taint.as_mut() {
+            taint[addr as usize] = tainted;
+        }
+    }
+
+    // Called for the next input whenever the queued inputs run out, instead of pausing.
+    // Values it returns are appended to the inputs as they are read.
+    pub fn with_input_callback(mut self, f: Box<dyn FnMut() -> T + Send>) -> VM<T> {
+        self.input_callback = Some(f);
+        self
+    }
+
+    pub fn with_output_transform(mut self, f: Box<dyn FnMut(T) -> T + Send>) -> VM<T> {
+        self.output_transform = Some(f);
+        self
+    }
+
+    pub fn with_history(mut self, enabled: bool) -> VM<T> {
+        self.track_history = enabled;
+        self
+    }
+
+    // Undo and redo only move memory and ip; consumed inputs and produced
+    // outputs are not rewound. Undoing past a HALT lets the VM run again,
+    // as after clear_halt; a VM halted by an error stays halted.
+    pub fn undo(&mut self) -> bool {
+        match self.history.pop() {
+            Some(record) => {
+                self.program[record.addr] = record.old;
+                self.ip = record.ip;
+                let _ = self.clear_halt();
+                self.redo_history.push(record);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn redo(&mut self) -> bool {
+        match self.redo_history.pop() {
+            Some(record) => {
+                self.program[record.addr] = record.new;
+                self.ip = record.ip;
+                if let Ok((instr, _)) = self.fetch_instr() {
+                    self.step(instr.steps_next);
+                }
+                self.history.push(record);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn with_ascii_script(mut self, script: &str) -> Result<VM<T>, VmError> {
+        if let Some(c) = script.chars().find(|c| !c.is_ascii()) {
+            return Err(VmError::NonAsciiInput(c));
+        }
+        self.ascii_inputs(script);
+        Ok(self)
+    }
+
+    pub fn ascii_inputs(&mut self, text: &str) {
+        for byte in text.bytes() {
+            self.push_input(T::from(byte));
+        }
+    }
+
+    // Outputs in 0..=127 as text; anything else is left to non_ascii_outputs.
+    pub fn outputs_as_string(&self) -> String {
+        self.outputs.iter()
+            .map(|value| value.to_i64())
+            .filter(|value| (0..=127).contains(value))
+            .map(|value| value as u8 as char)
+            .collect()
+    }
+
+    pub fn non_ascii_outputs(&self) -> Vec<T> {
+        self.outputs.iter().copied().filter(|value| !(0..=127).contains(&value.to_i64())).collect()
+    }
+
+    pub fn with_max_steps(mut self, n: u64) -> VM<T> {
+        self.max_steps = Some(n);
+        self
+    }
+
+    // Fails with InfiniteLoop once an ip is executed more than `limit` times with no
+    // input, output or change to memory in between.
+    pub fn with_loop_detection(mut self, limit: usize) -> VM<T> {
+        self.loop_limit = Some(limit);
+        self
+    }
+
+    pub fn with_max_jumps(mut self, n: usize) -> VM<T> {
+        self.max_jumps = Some(n);
+        self
+    }
+
+    pub fn with_strict_halt(mut self, strict: bool) -> VM<T> {
+        self.strict_halt = strict;
+        self
+    }
+
+    pub fn with_step_delay(mut self, d: Duration) -> VM<T> {
+        self.step_delay = Some(d);
+        self
+    }
+
+    pub fn with_exec_log(mut self, enabled: bool) -> VM<T> {
+        self.log_exec = enabled;
+        self
+    }
+
+    pub fn exec_log(&self) -> &[ExecRecord<T>] {
+        &self.exec_log
+    }
+
+    fn log_event(&mut self, event: Event<T>) {
+        if !self.log_exec {
+            return;
+        }
+        if let Some(record) = self.exec_log.last_mut() {
+            record.events.push(event);
+        }
+    }
+
+    pub fn alias_opcode(&mut self, from: i32, to: i32) {
+        self.aliases.insert(from, to);
+    }
+
+    pub fn to_source(&self) -> String {
+        self.program.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(",")
+    }
+
+    pub fn full_scalar_snapshot(&self) -> ScalarState {
+        ScalarState {
+            ip: self.ip,
+            in_p: self.in_p,
+            out_p: self.out_p,
+            out_rp: self.out_rp,
+            halted: self.halted,
+            halt_reason: self.halt_reason.clone(),
+            waiting_for_input: self.waiting_for_input,
+            relative_base: self.relative_base,
+            jumps: self.jumps,
+            steps: self.steps,
+        }
+    }
+
+    pub fn restore_scalars(&mut self, s: ScalarState) {
+        self.ip = s.ip;
+        self.in_p = s.in_p;
+        self.out_p = s.out_p;
+        self.out_rp = s.out_rp;
+        self.halted = s.halted;
+        self.halt_reason = s.halt_reason;
+        self.waiting_for_input = s.waiting_for_input;
+        self.relative_base = s.relative_base;
+        self.jumps = s.jumps;
+        self.steps = s.steps;
+    }
+
+    // Untraced memory access for callers, e.g. to patch a noun and verb before running.
+    // Addresses past the end read as zero, within the same limits as read_mem.
+    pub fn get_mem(&self, addr: usize) -> Result<T, VmError> {
+        match self.program.get(addr) {
+            Some(&value) => Ok(value),
+            None if self.grow_memory && addr < MAX_MEMORY => Ok(T::from(0)),
+            None => Err(VmError::AddressOutOfRange(addr as i64)),
+        }
+    }
+
+    pub fn set_mem(&mut self, addr: usize, value: T) -> Result<(), VmError> {
+        self.touch_mem(addr)?;
+        self.program[addr] = value;
+        Ok(())
+    }
+
+    pub fn read_mem(&mut self, addr: i64) -> Result<T, VmError> {
+        if addr < 0 {
+            return Err(VmError::NegativeAddress(addr));
+        }
+        self.touch_mem(addr as usize)?;
+        let value = self.program[addr as usize];
+        trace!(self, "Reading [{}] = {}", addr, value);
+        self.log_event(Event::Read { addr, value });
+        Ok(value)
+    }
+
+    pub fn write_mem(&mut self, addr: i64, value: T) -> Result<(), VmError> {
+        if addr < 0 {
+            return Err(VmError::NegativeAddress(addr));
+        }
+        self.touch_mem(addr as usize)?;
+        trace!(self, "Writing [{}] = {}", addr, value);
+        self.log_event(Event::Write { addr, value });
+        if self.program[addr as usize] != value {
+            self.loop_visits.clear();
+        }
+        if self.track_history {
+            let old = self.program[addr as usize];
+            self.history.push(WriteRecord { ip: self.ip, addr: addr as usize, old, new: value });
+            self.redo_history.clear();
+        }
+        self.program[addr as usize] = value;
+        Ok(())
+    }
+
+
+    pub fn fetch_instr(&self) -> Result<(Instruction, ParaModes), VmError> {
+        let instruction = match self.program.get(self.ip) {
+            Some(word) => word.to_i64(),
+            None => return Err(VmError::AddressOutOfRange(self.ip as i64)),
+        };
+        let para_modes = ParaModes::new(instruction);
+//        println!("Fetching instruction at [{}] = {}", self.ip, instruction);
+        let opcode = (instruction % 100) as i32;
+        let opcode = *self.aliases.get(&opcode).unwrap_or(&opcode);
+        match instruction_for(opcode) {
+            Some(instr) => Ok((instr, para_modes)),
+            None => Err(VmError::UnknownOpcode(opcode)),
+        }
+    }
+
+    // The instruction at ip as the disassembler shows it, and how many words it takes.
+    // Like the disassembler, a word that does not decode is shown as one word of DATA.
+    pub fn current_instruction(&self) -> Result<(String, usize), VmError> {
+        let word = match self.program.get(self.ip) {
+            Some(word) => word.to_i64(),
+            None => return Err(VmError::AddressOutOfRange(self.ip as i64)),
+        };
+        let instr = match self.fetch_instr() {
+            Ok((instr, _)) => instr,
+            Err(_) => return Ok((format!("DATA {}", word), 1)),
+        };
+        if self.ip + instr.steps_next > self.program.len() {
+            return Err(VmError::TruncatedInstruction { ip: self.ip, opcode: instr.opcode });
+        }
+        let words: Vec<i64> = iter::once(word)
+            .chain((1..instr.steps_next).map(|n| self.program[self.ip + n].to_i64()))
+            .collect();
+        Ok((format_instruction(instr, &words), instr.steps_next.max(1)))
+    }
+
+    pub fn current_opcode(&self) -> Result<i32, VmError> {
+        Ok(self.fetch_instr()?.0.opcode)
+    }
+
+    pub fn explain_current(&self) -> String {
+        let start = self.ip.saturating_sub(3);
+        let end = (self.ip + 4).min(self.program.len());
+        let mut text = format!("ip={} program[{}..{}]=", self.ip, start, end);
+        for addr in start..end {
+            if addr == self.ip {
+                text.push_str(&format!("[{}] ", self.program[addr]));
+            } else {
+                text.push_str(&format!("{} ", self.program[addr]));
+            }
+        }
+        if self.ip >= self.program.len() {
+            text.push_str("ip out of range");
+            return text;
+        }
+        match self.fetch_instr() {
+            Ok((instr, modes)) => {
+                text.push_str(&format!("{} {}", instr, modes));
+                for n in 1..instr.steps_next {
+                    let arg = match self.program.get(self.ip + n) {
+                        Some(&arg) => arg,
+                        None => break,
+                    };
+                    let mode = modes.mode(n as i32);
+                    if mode == MODE_VAL {
+                        text.push_str(&format!(" #{}", arg));
+                    } else {
+                        let addr = match self.dest_addr(n, mode) {
+                            Ok(addr) => addr,
+                            Err(_) => break,
+                        };
+                        let sigil = if mode == MODE_REL { format!("~{}->", arg) } else { String::from("@") };
+                        match self.program.get(addr as usize).filter(|_| addr >= 0) {
+                            Some(value) => text.push_str(&format!(" {}{}={}", sigil, addr, value)),
+                            None => text.push_str(&format!(" {}{}=?", sigil, addr)),
+                        }
+                    }
+                }
+            }
+            Err(error) => text.push_str(&error.to_string()),
+        }
+        text
+    }
+
+    // Operand n of the current instruction, or TruncatedInstruction if the program ends first.
+    fn fetch_arg(&self, n: usize) -> Result<T, VmError> {
+        match self.program.get(self.ip + n) {
+            Some(&arg) => Ok(arg),
+            None => Err(VmError::TruncatedInstruction {
+                ip: self.ip,
+                opcode: (self.program[self.ip].to_i64() % 100) as i32,
+            }),
+        }
+    }
+
+    // Parameter n of the current instruction, rejected if its mode is unknown
+    // or it is a negative position address.
+    fn decode_param(&self, n: usize, mode: i32) -> Result<Param, VmError> {
+        let param = Param::new(self.fetch_arg(n)?.to_i64(), mode);
+        if !param.is_valid() {
+            if param.is_reference() {
+                return Err(VmError::NegativeAddress(param.value));
+            }
+            return Err(VmError::BadMode(mode));
+        }
+        Ok(param)
+    }
+
+    pub fn fetch_arg_value(&mut self, n: usize, mode: i32) -> Result<T, VmError> {
+        let param = self.decode_param(n, mode)?;
+        if param.is_value() {
+            return self.fetch_arg(n);
+        }
+        if param.is_relative() {
+            return self.read_mem(param.value + self.relative_base);
+        }
+        self.read_mem(param.value)
+    }
+
+    // Operand n tagged with where its value came from, for traces:
+    // "[5]=37" from address 5, "=37" immediate, "~3=37" relative offset 3.
+    fn operand(&self, n: usize, modes: &ParaModes, value: T) -> String {
+        let arg = self.program.get(self.ip + n).copied().unwrap_or(value);
+        match modes.mode(n as i32) {
+            MODE_VAL => format!("={}", value),
+            MODE_REL => format!("~{}={}", arg, value),
+            _ => format!("[{}]={}", arg, value),
+        }
+    }
+
+    // The address parameter n refers to: relative to the relative base in mode 2.
+    fn dest_addr(&self, n: usize, mode: i32) -> Result<i64, VmError> {
+        let arg = self.fetch_arg(n)?.to_i64();
+        Ok(if mode == MODE_REL { arg + self.relative_base } else { arg })
+    }
+
+    fn step(&mut self, n: usize) {
+        self.ip += n;
+    }
+
+    // Counts the jump and returns the checked target address.
+    pub fn jump(&mut self, dest: i64) -> Result<usize, VmError> {
+        trace!(self, "Goto {}", dest);
+        if self.max_jumps.is_some_and(|max| self.jumps >= max) {
+            return Err(VmError::JumpLimitExceeded);
+        }
+        self.jumps += 1;
+        self.log_event(Event::Jump(dest));
+        if dest < 0 {
+            return Err(VmError::NegativeAddress(dest));
+        }
+        if dest as usize >= self.program.len() {
+            return Err(VmError::JumpOutOfRange(dest as usize));
+        }
+        Ok(dest as usize)
+    }
+
+    pub fn jump_to(&mut self, addr: usize) -> Result<(), VmError> {
+        if addr >= self.program.len() {
+            return Err(VmError::JumpOutOfRange(addr));
+        }
+        self.ip = addr;
+        self.waiting_for_input = false;
+        Ok(())
+    }
+
+    pub fn input_cursor(&self) -> usize {
+        self.in_p
+    }
+
+    pub fn set_input_cursor(&mut self, pos: usize) -> Result<(), VmError> {
+        if pos > self.inputs.len() {
+            return Err(VmError::InputCursorOutOfRange(pos));
+        }
+        self.in_p = pos;
+        Ok(())
+    }
+
+    pub fn peek_input(&self) -> Option<T> {
+        self.inputs.get(self.in_p).copied()
+    }
+
+    pub fn pending_inputs(&self) -> &[T] {
+        &self.inputs[self.in_p.min(self.inputs.len())..]
+    }
+
+    pub fn read_input(&mut self) -> Option<T> {
+        if !self.has_input() {
+            if let Some(callback) = self.input_callback.as_mut() {
+                let input = callback();
+                self.inputs.push(input);
+            } else if let Some(Ok(input)) = self.input_channel.as_ref().map(|receiver| receiver.recv()) {
+                self.inputs.push(input);
+            }
+        }
+        if self.has_input() {
+            let input = self.inputs[self.in_p];
+            self.in_p += 1;
+            self.loop_visits.clear();
+            self.log_event(Event::Input(input));
+            Some(input)
+        } else {
+            None
+        }
+    }
+
+    fn i_add(&mut self, modes: &ParaModes) -> Result<usize, VmError> {
+        let param1 = self.fetch_arg_value(1, modes.mode(1))?;
+        let param2 = self.fetch_arg_value(2, modes.mode(2))?;
+        let dest = self.dest_addr(3, modes.mode(3))?;
+        let tainted = self.param_taint(1, modes.mode(1)) || self.param_taint(2, modes.mode(2));
+        trace!(self, "I_ADD [{}] <- {} + {}", dest, self.operand(1, modes, param1), self.operand(2, modes, param2));
+        let value = param1.checked_add(param2)
+            .ok_or(VmError::ArithmeticOverflow { opcode: I_ADD.opcode, a: param1.to_i64(), b: param2.to_i64() })?;
+        self.write_mem(dest, value)?;
+        self.set_taint(dest, tainted);
+        Ok(self.ip + I_ADD.steps_next)
+    }
+
+    fn i_mul(&mut self, modes: &ParaModes) -> Result<usize, VmError> {
+        let param1 = self.fetch_arg_value(1, modes.mode(1))?;
+        let param2 = self.fetch_arg_value(2, modes.mode(2))?;
+        let dest = self.dest_addr(3, modes.mode(3))?;
+        trace!(self, "I_MUL [{}] <- {} * {}", dest, self.operand(1, modes, param1), self.operand(2, modes, param2));
+        let value = param1.checked_mul(param2)
+            .ok_or(VmError::ArithmeticOverflow { opcode: I_MUL.opcode, a: param1.to_i64(), b: param2.to_i64() })?;
+        let tainted = self.param_taint(1, modes.mode(1)) || self.param_taint(2, modes.mode(2));
+        self.write_mem(dest, value)?;
+        self.set_taint(dest, tainted);
+        Ok(self.ip + I_MUL.steps_next)
+    }
+
+    fn has_input(&self) -> bool {
+        self.inputs.len() > self.in_p
+    }
+
+    fn i_input(&mut self, modes: &ParaModes) -> Result<usize, VmError> {
+        let adr = self.dest_addr(1, modes.mode(1))?;
+        let input = self.read_input();
+        match input {
+            Some(input) => {
+                self.write_mem(adr, input)?;
+                self.set_taint(adr, true);
+                trace!(self, "I_INPUT [{}] input:{}", adr, input);
+                Ok(self.ip + I_IN.steps_next)
+            }
+            None => {
+                // ip stays on this instruction so it runs again once input arrives.
+                trace!(self, "Waiting for input at ip={}", self.ip);
+                self.waiting_for_input = true;
+                Ok(self.ip)
+            }
+        }
+    }
+
+    fn i_output(&mut self, modes: &ParaModes) -> Result<usize, VmError> {
+        let value = self.fetch_arg_value(1, modes.mode(1))?;
+        let mut output = value;
+        if let Some(transform) = self.output_transform.as_mut() {
+            output = transform(output);
+        }
+        match &self.output_channel {
+            Some(sender) => sender.send(output).map_err(|_| VmError::OutputChannelClosed)?,
+            None => {
+                self.outputs.push(output);
+                self.output_taint.push(self.param_taint(1, modes.mode(1)));
+            }
+        }
+        self.log_event(Event::Output(output));
+        self.out_p += 1;
+        self.loop_visits.clear();
+        trace!(self, "I_OUTPUT {}", self.operand(1, modes, value));
+        Ok(self.ip + I_OUT.steps_next)
+    }
+
+    fn i_jt(&mut self, modes: &ParaModes) -> Result<usize, VmError> {
+        let param = self.fetch_arg_value(1, modes.mode(1))?;
+        let dest = self.fetch_arg_value(2, modes.mode(2))?;
+        let jump = param != T::from(0);
+        trace!(self, "I_JT {} -> {} : {}", self.operand(1, modes, param), self.operand(2, modes, dest), jump);
+        if jump {
+            self.jump(dest.to_i64())
+        } else {
+            Ok(self.ip + I_JT.steps_next)
+        }
+    }
+
+    fn i_jf(&mut self, modes: &ParaModes) -> Result<usize, VmError> {
+        let param = self.fetch_arg_value(1, modes.mode(1))?;
+        let dest = self.fetch_arg_value(2, modes.mode(2))?;
+        let jump = param == T::from(0);
+        trace!(self, "I_JF {} -> {} : {}", self.operand(1, modes, param), self.operand(2, modes, dest), jump);
+        if jump {
+            self.jump(dest.to_i64())
+        } else {
+            Ok(self.ip + I_JF.steps_next)
+        }
+    }
+
+    fn i_lt(&mut self, modes: &ParaModes) -> Result<usize, VmError> {
+        let param1 = self.fetch_arg_value(1, modes.mode(1))?;
+        let param2 = self.fetch_arg_value(2, modes.mode(2))?;
+        let dest = self.dest_addr(3, modes.mode(3))?;
+        let res = if param1 < param2 { T::from(1) } else { T::from(0) };
+        let tainted = self.param_taint(1, modes.mode(1)) || self.param_taint(2, modes.mode(2));
+        trace!(self, "I_LT [{}] <- {} < {} : {}", dest, self.operand(1, modes, param1), self.operand(2, modes, param2), res);
+        self.write_mem(dest, res)?;
+        self.set_taint(dest, tainted);
+        Ok(self.ip + I_LT.steps_next)
+    }
+
+    fn i_eq(&mut self, modes: &ParaModes) -> Result<usize, VmError> {
+        let param1 = self.fetch_arg_value(1, modes.mode(1))?;
+        let param2 = self.fetch_arg_value(2, modes.mode(2))?;
+        let dest = self.dest_addr(3, modes.mode(3))?;
+        let res = if param1 == param2 { T::from(1) } else { T::from(0) };
+        let tainted = self.param_taint(1, modes.mode(1)) || self.param_taint(2, modes.mode(2));
+        trace!(self, "I_EQ [{}] <- {} == {} : {}", dest, self.operand(1, modes, param1), self.operand(2, modes, param2), res);
+        self.write_mem(dest, res)?;
+        self.set_taint(dest, tainted);
+        Ok(self.ip + I_EQ.steps_next)
+    }
+
+    fn i_adj(&mut self, modes: &ParaModes) -> Result<usize, VmError> {
+        let param = self.fetch_arg_value(1, modes.mode(1))?;
+        self.relative_base += param.to_i64();
+        trace!(self, "I_ADJ {} -> relative base {}", self.operand(1, modes, param), self.relative_base);
+        Ok(self.ip + I_ADJ.steps_next)
+    }
+
+    fn i_halt(&mut self) -> usize {
+        trace!(self, "I_HALT");
+        self.halted = true;
+        self.halt_reason = Some(HaltReason::Halt);
+        self.ip
+    }
+
+    pub fn clear_halt(&mut self) -> Result<(), VmError> {
+        if self.halt_reason == Some(HaltReason::Error) {
+            return Err(VmError::HaltedOnError);
+        }
+        self.halted = false;
+        self.halt_reason = None;
+        Ok(())
+    }
+
+    // Queued after any inputs not yet read; a VM waiting for input can then be resumed.
+    pub fn push_input(&mut self, input: T) {
+        self.inputs.push(input);
+        self.waiting_for_input = false;
+    }
+
+    pub fn read_output(&mut self) -> Result<T, VmError> {
+        trace!(self, "READ OUTPUT: {} {}", self.outputs.len(), self.out_rp);
+        let outv = *self.outputs.get(self.out_rp).ok_or(VmError::MissingOutput)?;
+        self.out_rp += 1;
+        Ok(outv)
+    }
+
+    pub fn exec_inst(&mut self) -> Result<(), VmError> {
+        self.decode_and_exec().map_err(|error| {
+            self.halted = true;
+            self.halt_reason = Some(HaltReason::Error);
+            VmError::Context {
+                context: self.explain_current(),
+                error: Box::new(error),
+            }
+        })
+    }
+
+    fn decode_and_exec(&mut self) -> Result<(), VmError> {
+        let (instr, modes) = self.fetch_instr()?;
+        let opcode = instr.opcode;
+        trace!(self, "Executing: {} ip={} {}", opcode, self.ip, modes);
+        if opcode != 99 && self.ip + instr.steps_next > self.program.len() {
+            trace!(self, "Truncated instruction at ip={}: {}", self.ip, opcode);
+            return Err(VmError::TruncatedInstruction { ip: self.ip, opcode });
+        }
+        let word = self.program[self.ip].to_i64();
+        if opcode == 99 && self.strict_halt && word / 100 != 0 {
+            return Err(VmError::InvalidMode(word));
+        }
+        for n in 1..instr.steps_next {
+            self.decode_param(n, modes.mode(n as i32))?;
+        }
+        if let Some(n) = instr.dest_param().filter(|&n| modes.mode(n as i32) == MODE_VAL) {
+            return Err(VmError::BadMode(modes.mode(n as i32)));
+        }
+        if self.log_exec {
+            self.exec_log.push(ExecRecord { ip: self.ip, opcode, modes: modes.modes, events: vec!() });
+        }
+        self.dispatch(opcode, &modes)?;
+        if !self.waiting_for_input {
+            self.steps += 1;
+            *self.stats.entry(opcode).or_insert(0) += 1;
+        } else if self.log_exec {
+            // The input instruction runs again on resume and is logged then.
+            self.exec_log.pop();
+        }
+        Ok(())
+    }
+
+    // Each handler returns the address of the next instruction; ip only moves here.
+    fn dispatch(&mut self, opcode: i32, modes: &ParaModes) -> Result<(), VmError> {
+        self.ip = match opcode {
+            99 => self.i_halt(),
+            1 => self.i_add(modes)?,
+            2 => self.i_mul(modes)?,
+            3 => self.i_input(modes)?,
+            4 => self.i_output(modes)?,
+            5 => self.i_jt(modes)?,
+            6 => self.i_jf(modes)?,
+            7 => self.i_lt(modes)?,
+            8 => self.i_eq(modes)?,
+            9 => self.i_adj(modes)?,
+            _ => {
+                trace!(self, "Unknown instruction: {}, halting", opcode);
+                self.i_halt()
+            }
+        };
+        Ok(())
+    }
+
+    // Back to the program as loaded, with fresh inputs. Options such as limits,
+    // aliases and tracking settings are kept; all run state and logs are cleared.
+    pub fn reset(&mut self, inputs: Vec<T>) {
+        self.program.clone_from(&self.pristine);
+        self.ip = 0;
+        self.in_p = 0;
+        self.out_p = 0;
+        self.out_rp = 0;
+        self.relative_base = 0;
+        self.halted = false;
+        self.halt_reason = None;
+        self.waiting_for_input = false;
+        self.inputs = inputs;
+        self.outputs.clear();
+        self.output_taint.clear();
+        self.exec_log.clear();
+        self.history.clear();
+        self.redo_history.clear();
+        self.loop_visits.clear();
+        self.jumps = 0;
+        self.steps = 0;
+        self.stats.clear();
+        self.stopped_at = None;
+        self.mem_high_water = None;
+        if let Some(taint) = self.taint.as_mut() {
+            *taint = vec![false; self.program.len()];
+        }
+    }
+
+    // How many times each opcode has executed.
+    pub fn stats(&self) -> &HashMap<i32, u64> {
+        &self.stats
+    }
+
+    // One "MNEMONIC count" line per executed opcode, in opcode order.
+    pub fn stats_summary(&self) -> String {
+        let mut opcodes: Vec<&i32> = self.stats.keys().collect();
+        opcodes.sort();
+        opcodes.iter()
+            .map(|&&opcode| {
+                let name = instruction_for(opcode).map_or("DATA", |instr| instr.mnemonic());
+                format!("{:<4} {}", name, self.stats[&opcode])
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn save_state(&self) -> String where T: serde::Serialize {
+        serde_json::to_string(self).expect("VM state is always serializable")
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn outputs(&self) -> &[T] {
+        &self.outputs
+    }
+
+    // Drains the buffered outputs; the VM keeps running and buffering afterwards.
+    pub fn take_outputs(&mut self) -> Vec<T> {
+        self.out_p = 0;
+        self.out_rp = 0;
+        self.output_taint.clear();
+        std::mem::take(&mut self.outputs)
+    }
+
+    pub fn last_output(&self) -> Option<T> {
+        self.outputs.last().copied()
+    }
+
+    pub fn output_count(&self) -> usize {
+        self.outputs.len()
+    }
+
+    pub fn is_runnable(&self) -> bool {
+        !self.halted && !self.waiting_for_input
+    }
+
+    // Executes one instruction and reports whether the VM has halted.
+    // Once halted this is a no-op that keeps returning true.
+    pub fn single_step(&mut self) -> Result<bool, VmError> {
+        if !self.halted {
+            if self.max_steps.is_some_and(|max| self.steps >= max) {
+                return Err(VmError::StepLimitExceeded);
+            }
+            self.check_loop()?;
+            self.exec_inst()?;
+            if !self.waiting_for_input {
+                self.stopped_at = None;
+            }
+        }
+        Ok(self.halted)
+    }
+
+    fn check_loop(&mut self) -> Result<(), VmError> {
+        if let Some(limit) = self.loop_limit {
+            let visits = self.loop_visits.entry(self.ip).or_insert(0);
+            *visits += 1;
+            if *visits > limit {
+                return Err(VmError::InfiniteLoop { ip: self.ip });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn resume(&mut self) -> Result<(), VmError> {
+        trace!(self, "resuming vm={}", self);
+        self.waiting_for_input = false;
+        while self.is_runnable() {
+            self.single_step()?;
+            if let Some(delay) = self.step_delay {
+                thread::sleep(delay);
+            }
+        }
+        trace!(self, "end vm={}", self);
+        Ok(())
+    }
+
+    pub fn run_until_output(&mut self) -> Result<OutputEvent<T>, VmError> {
+        let produced = self.outputs.len();
+        while self.is_runnable() {
+            self.single_step()?;
+            if self.outputs.len() > produced {
+                return Ok(OutputEvent::Output(self.outputs[produced]));
+            }
+        }
+        if self.waiting_for_input {
+            Ok(OutputEvent::NeedsInput)
+        } else {
+            Ok(OutputEvent::Halted)
+        }
+    }
+
+    pub fn run_until_input_count(&mut self, n: usize) -> Result<(), VmError> {
+        while self.is_runnable() && self.input_cursor() < n {
+            self.single_step()?;
+        }
+        Ok(())
+    }
+
+    pub fn into_outputs(mut self) -> impl Iterator<Item = Result<T, VmError>> {
+        let mut failed = false;
+        iter::from_fn(move || {
+            if failed {
+                return None;
+            }
+            match self.run_until_output() {
+                Ok(OutputEvent::Output(output)) => Some(Ok(output)),
+                Ok(_) => None,
+                Err(error) => {
+                    failed = true;
+                    Some(Err(error))
+                }
+            }
+        })
+    }
+
+    // Like into_outputs, but borrowing the VM. Iteration ends when the VM halts, needs input
+    // or fails; an error leaves the VM halted with HaltReason::Error.
+    pub fn outputs_iter(&mut self) -> impl Iterator<Item = T> + '_ {
+        iter::from_fn(move || match self.run_until_output() {
+            Ok(OutputEvent::Output(output)) => Some(output),
+            _ => None,
+        })
+    }
+
+    pub fn add_breakpoint(&mut self, ip: usize) {
+        self.breakpoints.insert(ip);
+    }
+
+    pub fn remove_breakpoint(&mut self, ip: usize) -> bool {
+        self.breakpoints.remove(&ip)
+    }
+
+    // Like run, but stops before executing an instruction at a breakpoint and returns true.
+    // Calling it again continues from the breakpoint it stopped on, unless the
+    // instruction there has since been executed, e.g. with single_step.
+    pub fn run_to_breakpoint(&mut self) -> Result<bool, VmError> {
+        self.waiting_for_input = false;
+        while self.is_runnable() {
+            if self.stopped_at != Some(self.ip) && self.breakpoints.contains(&self.ip) {
+                self.stopped_at = Some(self.ip);
+                return Ok(true);
+            }
+            self.single_step()?;
+        }
+        Ok(false)
+    }
+
+    // Hitting the step limit is an outcome here rather than an error.
+    pub fn run(&mut self) -> Result<RunOutcome, VmError> {
+        trace!(self, "start vm={}", self);
+        self.waiting_for_input = false;
+        while self.is_runnable() {
+            match self.single_step() {
+                Err(VmError::StepLimitExceeded) => return Ok(RunOutcome::StepLimit),
+                result => result?,
+            };
+            if let Some(delay) = self.step_delay {
+                thread::sleep(delay);
+            }
+        }
+        trace!(self, "end vm={}", self);
+        if self.halted {
+            Ok(RunOutcome::Halted)
+        } else {
+            Ok(RunOutcome::NeedsInput)
+        }
+    }
+}
+
+// Chainable construction for options that VM::new does not take.
+#[derive(Default)]
+pub struct VmBuilder<T = i64> {
+    program: Vec<T>,
+    inputs: Vec<T>,
+    verbose: bool,
+    max_steps: Option<u64>,
+    relative_base: i64,
+    trace_writer: Option<Box<dyn Write + Send>>,
+}
+
+impl<T: Cell> VmBuilder<T> {
+    pub fn new() -> VmBuilder<T> {
+        VmBuilder::default()
+    }
+
+    pub fn program(mut self, program: Vec<T>) -> VmBuilder<T> {
+        self.program = program;
+        self
+    }
+
+    pub fn inputs(mut self, inputs: Vec<T>) -> VmBuilder<T> {
+        self.inputs = inputs;
+        self
+    }
+
+    pub fn verbose(mut self, verbose: bool) -> VmBuilder<T> {
+        self.verbose = verbose;
+        self
+    }
+
+    pub fn max_steps(mut self, n: u64) -> VmBuilder<T> {
+        self.max_steps = Some(n);
+        self
+    }
+
+    pub fn relative_base(mut self, base: i64) -> VmBuilder<T> {
+        self.relative_base = base;
+        self
+    }
+
+    pub fn trace_writer(mut self, writer: Box<dyn Write + Send>) -> VmBuilder<T> {
+        self.trace_writer = Some(writer);
+        self
+    }
+
+    pub fn build(self) -> VM<T> {
+        VM {
+            pristine: self.program.clone(),
+            program: self.program,
+            ip: 0,
+            in_p: 0,
+            out_p: 0,
+            out_rp: 0,
+            halted: false,
+            halt_reason: None,
+            waiting_for_input: false,
+            relative_base: self.relative_base,
+            inputs: self.inputs,
+            outputs: vec!(),
+            aliases: HashMap::new(),
+            step_delay: None,
+            log_exec: false,
+            exec_log: vec!(),
+            strict_halt: false,
+            max_jumps: None,
+            max_steps: self.max_steps,
+            loop_limit: None,
+            loop_visits: HashMap::new(),
+            jumps: 0,
+            steps: 0,
+            stats: HashMap::new(),
+            breakpoints: HashSet::new(),
+            stopped_at: None,
+            track_history: false,
+            history: vec!(),
+            redo_history: vec!(),
+            output_transform: None,
+            input_callback: None,
+            taint: None,
+            output_taint: vec!(),
+            grow_memory: true,
+            mem_high_water: None,
+            output_channel: None,
+            input_channel: None,
+            verbose: self.verbose,
+            trace_writer: self.trace_writer,
+        }
+    }
+}
+
+pub struct Network {
+    pub vms: Vec<VM>,
+}
+
+impl Network {
+    pub fn new(program: &[i64], count: usize) -> Network {
+        let vms = (0..count).map(|addr| VM::from_slice(program, &[addr as i64])).collect();
+        Network { vms }
+    }
+
+    // Returns the packets that could not be delivered: those addressed to VMs that
+    // are not on the network or that have already halted.
+    pub fn run_until_idle(&mut self) -> Result<Vec<(i64, i64, i64)>, VmError> {
+        let mut dropped = vec!();
+        loop {
+            let mut packets = vec!();
+            for vm in self.vms.iter_mut().filter(|vm| !vm.halted) {
+                vm.resume()?;
+                while vm.outputs.len() - vm.out_rp >= 3 {
+                    packets.push((vm.read_output()?, vm.read_output()?, vm.read_output()?));
+                }
+            }
+            if packets.is_empty() {
+                return Ok(dropped);
+            }
+            for (dest, x, y) in packets {
+                if dest < 0 || self.vms.get(dest as usize).is_none_or(|vm| vm.halted) {
+                    dropped.push((dest, x, y));
+                    continue;
+                }
+                let vm = &mut self.vms[dest as usize];
+                vm.push_input(x);
+                vm.push_input(y);
+            }
+        }
+    }
+}
+
+// One amplifier per phase setting, tried in every order.
+pub fn max_thruster_signal(program: &[i64], phases: &[i64]) -> Result<i64, VmError> {
+    validate_phases(phases, 0..=4)?;
+    let mut amps = amplifiers(program, phases.len());
+    let mut top_value = i64::MIN;
+    for perm in Permutations::new(phases) {
+        let value = chain_signal(&mut amps, &perm)?;
+        if value > top_value {
+            top_value = value;
+        }
+    }
+    Ok(top_value)
+}
+
+// Same search as max_thruster_signal_feedback with each amplifier on its own thread.
+pub fn max_thruster_signal_feedback_threaded(program: &[i64], phases: &[i64]) -> Result<i64, VmError> {
+    let mut top_value = i64::MIN;
+    for perm in Permutations::new(phases) {
+        let value = test_amps_threaded(program, &perm)?;
+        if value > top_value {
+            top_value = value;
+        }
+    }
+    Ok(top_value)
+}
+
+// Amplifier i sends its outputs straight into amplifier i + 1's input channel,
+// and the last one feeds the first. The final signal is the value the last
+// amplifier left unread in the first amplifier's channel.
+pub fn test_amps_threaded(program: &[i64], params: &[i64]) -> Result<i64, VmError> {
+    validate_phases(params, 5..=9)?;
+    let (senders, receivers): (Vec<SyncSender<i64>>, Vec<Receiver<i64>>) =
+        params.iter().map(|_| mpsc::sync_channel(16)).unzip();
+    let mut receivers: Vec<Option<Receiver<i64>>> = receivers.into_iter().map(Some).collect();
+    let handles: Vec<_> = params.iter().enumerate()
+        .map(|(i, &phase)| {
+            let inputs = if i == 0 { vec!(phase, 0) } else { vec!(phase) };
+            let mut amp = VM::from_slice(program, &inputs)
+                .with_input_channel(receivers[i].take().unwrap())
+                .with_output_channel(senders[(i + 1) % params.len()].clone());
+            thread::spawn(move || {
+                let result = amp.run();
+                // Hang up so the next amplifier cannot block on an input that will never come.
+                amp.output_channel = None;
+                (amp, result)
+            })
+        })
+        .collect();
+    drop(senders);
+    let mut amps = vec!();
+    for handle in handles {
+        let (amp, result) = handle.join().expect("amplifier thread panicked");
+        result?;
+        amps.push(amp);
+    }
+    Ok(amps.first()
+        .and_then(|amp| amp.input_channel.as_ref())
+        .and_then(|receiver| receiver.try_iter().last())
+        .unwrap_or(0))
+}
+
+pub fn max_thruster_signal_feedback(program: &[i64], phases: &[i64]) -> Result<i64, VmError> {
+    validate_phases(phases, 5..=9)?;
+    let mut amps = amplifiers(program, phases.len());
+    let mut top_value = i64::MIN;
+    for perm in Permutations::new(phases) {
+        let value = feedback_signal(&mut amps, &perm)?;
+        if value > top_value {
+            top_value = value;
+        }
+    }
+    Ok(top_value)
+}
+
+// Every ordering of items in lexicographic order; the empty slice has one.
+pub fn permutations(items: &[i64]) -> Vec<Vec<i64>> {
+    Permutations::new(items).collect()
+}
+
+// Every ordering of the items in lexicographic order, produced one at a time.
+pub struct Permutations {
+    next: Option<Vec<i64>>,
+}
+
+impl Permutations {
+    pub fn new(items: &[i64]) -> Permutations {
+        let mut items = items.to_vec();
+        items.sort();
+        Permutations { next: Some(items) }
+    }
+}
+
+impl Iterator for Permutations {
+    type Item = Vec<i64>;
+
+    fn next(&mut self) -> Option<Vec<i64>> {
+        let current = self.next.take()?;
+        // Swap the rightmost ascent with the smallest larger item after it and reverse the tail.
+        // The last permutation has no ascent.
+        if let Some(i) = (1..current.len()).rev().find(|&i| current[i - 1] < current[i]) {
+            let mut items = current.clone();
+            let j = (i..items.len()).rev().find(|&j| items[j] > items[i - 1]).unwrap();
+            items.swap(i - 1, j);
+            items[i..].reverse();
+            self.next = Some(items);
+        }
+        Some(current)
+    }
+}
+
+// Runs the program to halt and returns everything it output.
+pub fn run_program(program: Vec<i64>, inputs: Vec<i64>) -> Result<Vec<i64>, VmError> {
+    let mut vm = VM::new(program, inputs);
+    if vm.run()? != RunOutcome::Halted {
+        return Err(VmError::InputExhausted);
+    }
+    Ok(vm.take_outputs())
+}
+
+pub fn run_capped(vm: VM, step_cap: usize) -> Result<VM, VmError> {
+    let mut vm = vm.with_max_steps(step_cap as u64);
+    while vm.is_runnable() {
+        vm.single_step()?;
+    }
+    Ok(vm)
+}
+
+pub fn steps_to_halt(program: &[i64], inputs: &[i64], cap: usize) -> Result<u64, VmError> {
+    let vm = run_capped(VM::from_slice(program, inputs), cap)?;
+    if !vm.halted {
+        return Err(VmError::InputExhausted);
+    }
+    Ok(vm.steps)
+}
+
+pub fn requires_extended_memory(program: &[i64], inputs: &[i64], cap: usize) -> Result<bool, VmError> {
+    let vm = run_capped(VM::from_slice(program, inputs), cap)?;
+    Ok(vm.mem_high_water.is_some_and(|high| high >= program.len()))
+}
+
+// Input sets whose run fails or exceeds the cap are skipped.
+pub fn slowest_input(program: &[i64], input_sets: &[Vec<i64>], cap: usize) -> Option<(usize, u64)> {
+    let mut slowest: Option<(usize, u64)> = None;
+    for (index, inputs) in input_sets.iter().enumerate() {
+        if let Ok(steps) = steps_to_halt(program, inputs, cap) {
+            if slowest.is_none_or(|(_, most)| steps > most) {
+                slowest = Some((index, steps));
+            }
+        }
+    }
+    slowest
+}
+
+// A VM that halts or blocks on input earlier stays parked on that instruction.
+pub fn opcode_at_step(program: &[i64], inputs: &[i64], k: usize) -> Result<i32, VmError> {
+    let mut vm = VM::from_slice(program, inputs);
+    for _ in 1..k {
+        if !vm.is_runnable() {
+            break;
+        }
+        vm.exec_inst()?;
+    }
+    vm.current_opcode()
+}
+
+pub fn output_count_for(program: &[i64], inputs: &[i64], step_cap: usize) -> Result<usize, VmError> {
+    Ok(run_capped(VM::from_slice(program, inputs), step_cap)?.outputs.len())
+}
+
+// A sampling check: the programs agree on these inputs, which proves nothing about
+// other inputs. A run that fails on either side counts as a difference.
+pub fn behaviorally_equivalent(a: &[i64], b: &[i64], input_sets: &[Vec<i64>], step_cap: usize) -> bool {
+    input_sets.iter().all(|inputs| {
+        match (run_capped(VM::from_slice(a, inputs), step_cap), run_capped(VM::from_slice(b, inputs), step_cap)) {
+            (Ok(vm_a), Ok(vm_b)) => vm_a.outputs == vm_b.outputs,
+            _ => false,
+        }
+    })
+}
+
+pub fn rank_phase_settings(program: &[i64], phases: &[i64], feedback: bool) -> Result<Vec<(Vec<i64>, i64)>, VmError> {
+    validate_phases(phases, if feedback { 5..=9 } else { 0..=4 })?;
+    let mut amps = amplifiers(program, phases.len());
+    let mut ranked: Vec<(Vec<i64>, i64)> = Permutations::new(phases)
+        .map(|perm| {
+            let signal = if feedback {
+                feedback_signal(&mut amps, &perm)?
+            } else {
+                chain_signal(&mut amps, &perm)?
+            };
+            Ok((perm, signal))
+        })
+        .collect::<Result<_, VmError>>()?;
+    ranked.sort_by_key(|entry| std::cmp::Reverse(entry.1));
+    Ok(ranked)
+}
+
+pub fn amplify_trace(program: &[i64], phases: &[i64]) -> Result<Vec<Vec<i64>>, VmError> {
+    validate_phases(phases, 5..=9)?;
+    let mut amps: Vec<VM> = phases.iter().map(|&phase| VM::from_slice(program, &[phase])).collect();
+    let mut trace = vec!();
+    let mut signal = 0;
+    loop {
+        let mut cycle = vec!();
+        for amp in amps.iter_mut() {
+            amp.push_input(signal);
+            match amp.run_until_output()? {
+                OutputEvent::Output(output) => {
+                    signal = output;
+                    cycle.push(output);
+                }
+                _ => return Ok(trace),
+            }
+        }
+        trace.push(cycle);
+    }
+}
+
+pub fn validate_phases(phases: &[i64], range: RangeInclusive<i64>) -> Result<(), VmError> {
+    for (i, phase) in phases.iter().enumerate() {
+        if !range.contains(phase) {
+            return Err(VmError::PhaseOutOfRange(*phase));
+        }
+        if phases[..i].contains(phase) {
+            return Err(VmError::DuplicatePhase(*phase));
+        }
+    }
+    Ok(())
+}
+
+pub fn test_amps(program: Vec<i64>, params: Vec<i64>) -> Result<i64, VmError> {
+    validate_phases(&params, 0..=4)?;
+    chain_signal(&mut amplifiers(&program, params.len()), &params)
+}
+
+pub fn test_amps_2(program: Vec<i64>, params: Vec<i64>) -> Result<i64, VmError> {
+    validate_phases(&params, 5..=9)?;
+    feedback_signal(&mut amplifiers(&program, params.len()), &params)
+}
+
+// Searches reuse one set of amplifiers and reset them for each phase setting,
+// which copies the program back from each VM's pristine template.
+fn amplifiers(program: &[i64], count: usize) -> Vec<VM> {
+    (0..count).map(|_| VM::from_slice(program, &[])).collect()
+}
+
+fn chain_signal(amps: &mut [VM], phases: &[i64]) -> Result<i64, VmError> {
+    let mut signal = 0;
+    for (amp, &phase) in amps.iter_mut().zip(phases) {
+        amp.reset(vec!(phase, signal));
+        amp.run()?;
+        signal = amp.read_output()?;
+    }
+    Ok(signal)
+}
+
+// Signals go round the loop until the last amplifier halts.
+fn feedback_signal(amps: &mut [VM], phases: &[i64]) -> Result<i64, VmError> {
+    for (amp, &phase) in amps.iter_mut().zip(phases) {
+        amp.reset(vec!(phase));
+    }
+    let mut signal = 0;
+    loop {
+        for amp in amps.iter_mut() {
+            amp.push_input(signal);
+            amp.resume()?;
+            signal = amp.read_output()?;
+        }
+        if amps.last().is_none_or(|amp| amp.halted) {
+            return Ok(signal);
+        }
+    }
+}
+
+pub fn noun_verb(program: &[i64]) -> Option<(i64, i64)> {
+    if program.len() < 3 {
+        return None;
+    }
+    Some((program[1], program[2]))
+}
+
+// Day 2: the first noun and verb in 0..=99 that, patched into addresses 1 and 2,
+// leave target at address 0. Pairs whose run fails are skipped.
+pub fn find_noun_verb(program: &[i64], target: i64) -> Option<(i64, i64)> {
+    if program.len() < 3 {
+        return None;
+    }
+    let mut vm = VM::from_slice(program, &[]);
+    for noun in 0..=99 {
+        for verb in 0..=99 {
+            vm.reset(vec!());
+            if vm.set_mem(1, noun).and_then(|_| vm.set_mem(2, verb)).and_then(|_| vm.run()).is_err() {
+                continue;
+            }
+            if vm.get_mem(0) == Ok(target) {
+                return Some((noun, verb));
+            }
+        }
+    }
+    None
+}
+
+// The line the binary prints for each puzzle answer, e.g. "Part 1: 79723".
+pub fn answer_line(part: u32, answer: i64) -> String {
+    format!("Part {}: {}", part, answer)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DebugCommand {
+    Step,
+    Continue,
+    Break(usize),
+    Mem(usize),
+    Input(i64),
+    Regs,
+    Quit,
+}
+
+// None for anything that is not exactly a command and its argument.
+pub fn parse_debug_command(line: &str) -> Option<DebugCommand> {
+    let mut words = line.split_whitespace();
+    let command = match (words.next()?, words.next()) {
+        ("step", None) => DebugCommand::Step,
+        ("continue", None) => DebugCommand::Continue,
+        ("break", Some(ip)) => DebugCommand::Break(ip.parse().ok()?),
+        ("mem", Some(addr)) => DebugCommand::Mem(addr.parse().ok()?),
+        ("input", Some(value)) => DebugCommand::Input(value.parse().ok()?),
+        ("regs", None) => DebugCommand::Regs,
+        ("quit", None) => DebugCommand::Quit,
+        _ => return None,
+    };
+    if words.next().is_some() {
+        return None;
+    }
+    Some(command)
+}
+
+// Reads one command per line until quit or the end of the input and reports on out.
+// VM errors are reported and the session carries on; only I/O errors end it early.
+pub fn debug_session<R: BufRead, W: Write>(vm: &mut VM, input: R, out: &mut W) -> io::Result<()> {
+    show_position(vm, out)?;
+    for line in input.lines() {
+        let line = line?;
+        let command = match parse_debug_command(&line) {
+            Some(command) => command,
+            None => {
+                writeln!(out, "unknown command: {}", line.trim())?;
+                continue;
+            }
+        };
+        match command {
+            DebugCommand::Step => {
+                let produced = vm.output_count();
+                let result = vm.single_step().map(|_| ());
+                report_run(vm, produced, result, out)?;
+            }
+            DebugCommand::Continue => {
+                let produced = vm.output_count();
+                let result = vm.run_to_breakpoint().map(|_| ());
+                report_run(vm, produced, result, out)?;
+            }
+            DebugCommand::Break(ip) => {
+                vm.add_breakpoint(ip);
+                writeln!(out, "breakpoint at {}", ip)?;
+            }
+            DebugCommand::Mem(addr) => match vm.get_mem(addr) {
+                Ok(value) => writeln!(out, "[{}] = {}", addr, value)?,
+                Err(error) => writeln!(out, "error: {}", error)?,
+            },
+            DebugCommand::Input(value) => vm.push_input(value),
+            DebugCommand::Regs => writeln!(out, "ip={} relative_base={} in_p={} out_p={} steps={}",
+                                           vm.ip, vm.relative_base, vm.in_p, vm.out_p, vm.steps)?,
+            DebugCommand::Quit => break,
+        }
+    }
+    Ok(())
+}
+
+// The outputs produced since `produced`, then the error or where the VM stopped.
+fn report_run<W: Write>(vm: &VM, produced: usize, result: Result<(), VmError>, out: &mut W) -> io::Result<()> {
+    for output in &vm.outputs()[produced..] {
+        writeln!(out, "output {}", output)?;
+    }
+    match result {
+        Ok(()) => show_position(vm, out),
+        Err(error) => writeln!(out, "error: {}", error),
+    }
+}
+
+fn show_position<W: Write>(vm: &VM, out: &mut W) -> io::Result<()> {
+    if vm.halted {
+        return writeln!(out, "halted");
+    }
+    if vm.waiting_for_input {
+        return writeln!(out, "waiting for input at ip={}", vm.ip);
+    }
+    match vm.current_instruction() {
+        Ok((text, _)) => writeln!(out, "ip={} next: {}", vm.ip, text),
+        Err(error) => writeln!(out, "ip={} {}", vm.ip, error),
+    }
+}
+
+// (name, program, inputs, expected outputs)
+pub type SampleProgram = (&'static str, Vec<i64>, Vec<i64>, Vec<i64>);
+
+// One or more per instruction, taken from the Advent of Code 2019 puzzle examples.
+pub fn sample_programs() -> Vec<SampleProgram> {
+    vec!(
+        ("add", vec!(1101, 100, -1, 4, 0), vec!(), vec!()),
+        ("mul", vec!(1002, 4, 3, 4, 33), vec!(), vec!()),
+        ("wide mul", vec!(1102, 3_000_000_000, 3_000_000_000, 7, 4, 7, 99, 0), vec!(), vec!(9_000_000_000_000_000_000)),
+        ("amplifier", vec!(3, 15, 3, 16, 1002, 16, 10, 16, 1, 16, 15, 15, 4, 15, 99, 0, 0), vec!(4, 0), vec!(4)),
+        ("echo", vec!(3, 0, 4, 0, 99), vec!(42), vec!(42)),
+        ("jt", vec!(3, 3, 1105, -1, 9, 1101, 0, 0, 12, 4, 12, 99, 1), vec!(5), vec!(1)),
+        ("jf", vec!(3, 12, 6, 12, 15, 1, 13, 14, 13, 4, 13, 99, -1, 0, 1, 9), vec!(0), vec!(0)),
+        ("lt", vec!(3, 9, 7, 9, 10, 9, 4, 9, 99, -1, 8), vec!(5), vec!(1)),
+        ("lt immediate", vec!(3, 3, 1107, -1, 8, 3, 4, 3, 99), vec!(9), vec!(0)),
+        ("eq", vec!(3, 9, 8, 9, 10, 9, 4, 9, 99, -1, 8), vec!(8), vec!(1)),
+        ("eq immediate", vec!(3, 3, 1108, -1, 8, 3, 4, 3, 99), vec!(7), vec!(0)),
+        ("compare to 8", vec!(3, 21, 1008, 21, 8, 20, 1005, 20, 22, 107, 8, 21, 20, 1006, 20, 31, 1106, 0, 36, 98, 0, 0,
+                              1002, 21, 125, 20, 4, 20, 1105, 1, 46, 104, 999, 1105, 1, 46, 1101, 1000, 1, 20, 4, 20,
+                              1105, 1, 46, 98, 99), vec!(7), vec!(999)),
+        ("relative base", vec!(109, 1, 204, -1, 99), vec!(), vec!(109)),
+        ("halt", vec!(99), vec!(), vec!()),
+    )
+}
+
+// Reads from stdin when the path is "-".
+pub fn read_program(path: &str) -> Result<Vec<i64>, ParseProgramError> {
+    let program = if path == "-" {
+        read_program_from(io::stdin().lock())?
+    } else {
+        read_program_from(BufReader::new(File::open(path)?))?
+    };
+    if program.is_empty() {
+        return Err(ParseProgramError::Empty);
+    }
+    Ok(program)
+}
+
+// The program may be spread over several lines; blank lines are skipped.
+pub fn read_program_from<R: BufRead>(reader: R) -> Result<Vec<i64>, ParseProgramError> {
+    let lines = reader.lines().collect::<io::Result<Vec<String>>>()?;
+    if lines.iter().all(|line| line.trim().is_empty()) {
+        return Ok(vec!());
+    }
+    parse_program(&lines.join("\n"))
+}
+
+#[cfg(feature = "flate2")]
+pub fn read_program_gz<P: AsRef<Path>>(path: P) -> Result<Vec<i64>, ParseProgramError> {
+    use std::io::Read;
+
+    let mut text = String::new();
+    flate2::read::GzDecoder::new(File::open(path)?).read_to_string(&mut text)?;
+    parse_program(&text)
+}
+
+// One program per line; blank lines and lines starting with '#' are skipped.
+pub fn read_programs<P: AsRef<Path>>(path: P) -> Result<Vec<Vec<i64>>, ParseProgramError> {
+    let mut programs = vec!();
+    for (index, line) in get_lines(path)?.enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        programs.push(parse_line(index + 1, line)?);
+    }
+    Ok(programs)
+}
+
+// A single comma-separated program; whitespace around the text and each token is ignored.
+// Non-empty lines are parsed in turn, so a line break may stand in for a comma, and a
+// comma ending any line but the last is allowed. Errors name the line they occur on.
+pub fn parse_program(src: &str) -> Result<Vec<i64>, ParseProgramError> {
+    let lines: Vec<(usize, &str)> = src.lines()
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+        .collect();
+    if lines.is_empty() {
+        return parse_line(1, "");
+    }
+    let mut program = vec!();
+    for (i, &(index, line)) in lines.iter().enumerate() {
+        let line = if i + 1 < lines.len() { line.strip_suffix(',').unwrap_or(line) } else { line };
+        program.extend(parse_line(index + 1, line)?);
+    }
+    Ok(program)
+}
+
+// Words are i64, so negative values such as the -1 sentinels in the Day 5 examples load as is.
+// A sign with no digits, like any other malformed word, is an InvalidToken.
+pub fn parse_line(line_no: usize, line: &str) -> Result<Vec<i64>, ParseProgramError> {
+    let mut result: Vec<i64> = vec!();
+    for (position, item) in line.split(',').enumerate() {
+        let item = item.trim();
+        match item.parse() {
+            Ok(byte) => result.push(byte),
+            Err(_) => return Err(ParseProgramError::InvalidToken {
+                line: line_no,
+                position,
+                token: item.to_string(),
+            }),
+        }
+    }
+    Ok(result)
+}
+
+fn get_lines<P>(file_name: P) -> io::Result<Lines<BufReader<File>>>
+    where P: AsRef<Path>, {
+    let file = File::open(file_name)?;
+    Ok(BufReader::new(file).lines())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_are_returned_instead_of_panicking() {
+        let mut vm = VM::new(vec!(42, 0, 0, 0), vec!());
+        assert_eq!(vm.run().unwrap_err().root_cause(), &VmError::UnknownOpcode(42));
+        let mut vm = VM::new(vec!(1, -1, 0, 0, 99), vec!());
+        assert_eq!(vm.run().unwrap_err().root_cause(), &VmError::NegativeAddress(-1));
+        let mut vm = VM::new(vec!(104, 1), vec!());
+        assert_eq!(vm.run().unwrap_err().root_cause(), &VmError::AddressOutOfRange(2));
+        let mut vm = VM::new(vec!(1105, 1, 100), vec!());
+        assert_eq!(vm.run().unwrap_err().root_cause(), &VmError::JumpOutOfRange(100));
+    }
+
+    #[test]
+    fn truncated_instruction_is_an_error() {
+        let mut vm = VM::new(vec!(1101, 1, 1, 5, 1), vec!());
+        assert_eq!(vm.run().unwrap_err().root_cause(), &VmError::TruncatedInstruction { ip: 4, opcode: 1 });
+        let mut vm = VM::new(vec!(1), vec!());
+        assert_eq!(vm.run().unwrap_err().root_cause(), &VmError::TruncatedInstruction { ip: 0, opcode: 1 });
+        let mut vm = VM::new(vec!(104, 1), vec!());
+        vm.ip = 2;
+        assert_eq!(vm.current_opcode(), Err(VmError::AddressOutOfRange(2)));
+    }
+
+    #[test]
+    fn missing_output_is_an_error() {
+        assert_eq!(max_thruster_signal(&[99], &[0, 1, 2, 3, 4]), Err(VmError::MissingOutput));
+        assert_eq!(max_thruster_signal_feedback(&[99], &[5, 6, 7, 8, 9]), Err(VmError::MissingOutput));
+        let mut vm = VM::new(vec!(104, 7, 99), vec!());
+        vm.run().unwrap();
+        assert_eq!(vm.read_output(), Ok(7));
+        assert_eq!(vm.read_output(), Err(VmError::MissingOutput));
+    }
+
+    #[test]
+    fn negative_signals_are_not_clamped_to_zero() {
+        assert_eq!(max_thruster_signal(&[3, 0, 3, 0, 104, -5, 99], &[0, 1]), Ok(-5));
+        let program = vec!(3, 15, 3, 16, 1002, 16, 10, 16, 1, 16, 15, 15, 4, 15, 99, 0, 0);
+        assert_eq!(max_thruster_signal(&program, &[0, 1, 2, 3, 4]), Ok(43210));
+    }
+
+    #[test]
+    fn amplify_trace_reports_errors() {
+        let program = vec!(3, 26, 1001, 26, -4, 26, 3, 27, 1002, 27, 2, 27, 1, 27, 26, 27, 4, 27, 1001, 28, -1, 28,
+                           1005, 28, 6, 99, 0, 0, 5);
+        let trace = amplify_trace(&program, &[9, 8, 7, 6, 5]).unwrap();
+        assert_eq!(trace.last().and_then(|cycle| cycle.last()), Some(&139629729));
+        assert_eq!(amplify_trace(&[42], &[5]).unwrap_err().root_cause(), &VmError::UnknownOpcode(42));
+        assert_eq!(amplify_trace(&program, &[0]), Err(VmError::PhaseOutOfRange(0)));
+    }
+
+    #[test]
+    fn validate_reports_the_failing_address() {
+        let error = validate(&[1101, 1, 2, 0, 77]).unwrap_err();
+        assert!(error.to_string().contains("at address 4"));
+        assert_eq!(error.root_cause(), &VmError::UnknownOpcode(77));
+        assert!(validate_with(&[77], &ValidateOptions::default()).is_err());
+        assert_eq!(validate(&[1101, 1, 2, 0, 99]), Ok(()));
+    }
+
+    #[test]
+    fn negative_opcodes_are_rejected_at_validation() {
+        let options = ValidateOptions { reject_negative_opcodes: true };
+        let error = validate_with(&[1101, 1, 2, 0, -99], &options).unwrap_err();
+        assert_eq!(error.root_cause(), &VmError::NegativeOpcode { addr: 4, word: -99 });
+        assert!(validate(&[1101, 1, 2, 0, -99]).is_err());
+    }
+
+    #[test]
+    fn network_forwards_packets_and_returns_dropped_ones() {
+        // Address 0 sends (10, 20) to address 1 and halts. Address 1 passes the packet
+        // back to address 0, which has halted, and on to address 9, which does not exist.
+        let program = vec!(3, 30, 1005, 30, 12, 104, 1, 104, 10, 104, 20, 99,
+                           3, 31, 3, 32, 104, 0, 4, 31, 4, 32, 104, 9, 4, 31, 4, 32, 99, 0, 0, 0, 0);
+        let mut network = Network::new(&program, 2);
+        assert_eq!(network.run_until_idle(), Ok(vec!((0, 10, 20), (9, 10, 20))));
+        assert!(network.vms.iter().all(|vm| vm.halted));
+    }
+
+    #[test]
+    fn read_program_from_any_reader() {
+        assert_eq!(read_program_from(&b"1,0,0,3,\n99\n"[..]).unwrap(), vec!(1, 0, 0, 3, 99));
+        assert_eq!(read_program_from(&b"\n  \n"[..]).unwrap(), Vec::<i64>::new());
+        match read_program("no-such-program.txt") {
+            Err(ParseProgramError::Io(error)) => assert_eq!(error.kind(), io::ErrorKind::NotFound),
+            other => panic!("expected a NotFound error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn outputs_go_to_the_output_channel() {
+        let (sender, receiver) = mpsc::sync_channel(4);
+        let mut vm = VM::new(vec!(104, 1, 104, 2, 99), vec!()).with_output_channel(sender);
+        vm.run().unwrap();
+        assert!(vm.outputs().is_empty());
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec!(1, 2));
+    }
+
+    #[test]
+    fn ascii_scripts_become_inputs() {
+        let vm = VM::new(vec!(99), vec!()).with_ascii_script("north\nsouth\n").unwrap();
+        assert_eq!(vm.inputs, b"north\nsouth\n".iter().map(|&b| b as i64).collect::<Vec<_>>());
+        assert_eq!(VM::new(vec!(99), vec!()).with_ascii_script("nörth").err(), Some(VmError::NonAsciiInput('ö')));
+    }
+
+    #[test]
+    fn pause_for_input_then_resume() {
+        let mut vm = VM::new(vec!(3, 0, 4, 0, 3, 0, 4, 0, 99), vec!());
+        assert_eq!(vm.run(), Ok(RunOutcome::NeedsInput));
+        vm.push_input(1);
+        vm.resume().unwrap();
+        assert!(vm.waiting_for_input);
+        vm.push_input(4);
+        vm.resume().unwrap();
+        assert!(vm.halted);
+        assert_eq!(vm.outputs(), &[1, 4]);
+    }
+
+    #[test]
+    fn sample_programs_produce_their_expected_outputs() {
+        for (name, program, inputs, expected) in sample_programs() {
+            assert_eq!(run_program(program, inputs), Ok(expected), "{}", name);
+        }
+        for program in [vec!(1101, 100, -1, 4, 0), vec!(1002, 4, 3, 4, 33)] {
+            let mut vm = VM::new(program, vec!());
+            vm.run().unwrap();
+            assert_eq!(vm.get_mem(4), Ok(99));
+        }
+    }
+
+    #[test]
+    fn jump_programs_compare_input_to_zero() {
+        for program in [vec!(3, 12, 6, 12, 15, 1, 13, 14, 13, 4, 13, 99, -1, 0, 1, 9),
+                        vec!(3, 3, 1105, -1, 9, 1101, 0, 0, 12, 4, 12, 99, 1)] {
+            assert_eq!(run_program(program.clone(), vec!(0)), Ok(vec!(0)));
+            assert_eq!(run_program(program, vec!(7)), Ok(vec!(1)));
+        }
+    }
+
+    #[test]
+    fn comparison_programs() {
+        // (program, expected outputs for inputs 7, 8 and 9)
+        let cases = [
+            (vec!(3, 9, 8, 9, 10, 9, 4, 9, 99, -1, 8), [0, 1, 0]),
+            (vec!(3, 9, 7, 9, 10, 9, 4, 9, 99, -1, 8), [1, 0, 0]),
+            (vec!(3, 3, 1108, -1, 8, 3, 4, 3, 99), [0, 1, 0]),
+            (vec!(3, 3, 1107, -1, 8, 3, 4, 3, 99), [1, 0, 0]),
+        ];
+        for (program, expected) in cases {
+            for (input, output) in [7, 8, 9].iter().copied().zip(expected) {
+                assert_eq!(run_program(program.clone(), vec!(input)), Ok(vec!(output)), "{:?} {}", program, input);
+            }
+        }
+    }
+
+    #[test]
+    fn parse_program_accepts_negative_words() {
+        assert_eq!(parse_program("3,3,1105,-1,9,1101,0,0,12,4,12,99,1").unwrap(),
+                   vec!(3, 3, 1105, -1, 9, 1101, 0, 0, 12, 4, 12, 99, 1));
+        assert!(matches!(parse_program("-"), Err(ParseProgramError::InvalidToken { .. })));
+    }
+
+    #[test]
+    fn aliased_opcodes_run_as_their_target() {
+        let program = vec!(1150, 2, 3, 7, 4, 7, 99, 0);
+        let mut vm = VM::new(program.clone(), vec!());
+        assert_eq!(vm.run().unwrap_err().root_cause(), &VmError::UnknownOpcode(50));
+        let mut vm = VM::new(program, vec!());
+        vm.alias_opcode(50, 1);
+        vm.run().unwrap();
+        assert_eq!(vm.outputs(), &[5]);
+    }
+
+    #[test]
+    fn truncated_instructions_are_rejected_before_running() {
+        let mut vm = VM::new(vec!(1101, 1, 1), vec!());
+        assert_eq!(vm.run().unwrap_err().root_cause(), &VmError::TruncatedInstruction { ip: 0, opcode: 1 });
+        assert_eq!(vm.program, vec!(1101, 1, 1));
+        assert_eq!(vm.steps, 0);
+    }
+
+    #[test]
+    fn from_slice_matches_new() {
+        let program = [3, 0, 4, 0, 99];
+        let borrowed = VM::from_slice(&program, &[5]);
+        let owned = VM::new(program.to_vec(), vec!(5));
+        assert_eq!(borrowed.program, owned.program);
+        assert_eq!(borrowed.pristine, owned.pristine);
+        assert_eq!(borrowed.inputs, owned.inputs);
+        assert_eq!((borrowed.ip, borrowed.in_p, borrowed.halted), (owned.ip, owned.in_p, owned.halted));
+    }
+
+    #[test]
+    fn to_source_round_trips_through_parse_program() {
+        let program = vec!(1002, 4, 3, 4, 33);
+        let mut vm = VM::new(program.clone(), vec!());
+        assert_eq!(vm.to_source(), "1002,4,3,4,33");
+        assert_eq!(parse_program(&vm.to_source()).unwrap(), program);
+        vm.run().unwrap();
+        assert_eq!(parse_program(&vm.to_source()).unwrap(), vec!(1002, 4, 3, 4, 99));
+    }
+
+    #[test]
+    fn execution_errors_show_the_instruction_at_ip() {
+        let mut vm = VM::new(vec!(104, 1, 42, 0, 0), vec!());
+        let error = vm.run().unwrap_err();
+        assert_eq!(error.root_cause(), &VmError::UnknownOpcode(42));
+        assert!(error.to_string().contains("ip=2 program[0..5]=104 1 [42] 0 0"), "{}", error);
+        assert!(vm.is_halted());
+    }
+
+    #[test]
+    fn step_delay_slows_each_instruction() {
+        let start = std::time::Instant::now();
+        let mut vm = VM::new(vec!(104, 1, 104, 2, 99), vec!()).with_step_delay(Duration::from_millis(5));
+        vm.run().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(15));
+        assert_eq!(vm.outputs(), &[1, 2]);
+    }
+
+    #[test]
+    fn noun_verb_reads_addresses_1_and_2() {
+        assert_eq!(noun_verb(&[1, 12, 2, 3, 99]), Some((12, 2)));
+        assert_eq!(noun_verb(&[1, 12]), None);
+    }
+
+    #[test]
+    fn exec_log_records_each_instruction() {
+        let mut vm = VM::new(vec!(3, 0, 4, 0, 99), vec!(7)).with_exec_log(true);
+        vm.run().unwrap();
+        assert_eq!(vm.exec_log(), &[
+            ExecRecord { ip: 0, opcode: 3, modes: [0, 0, 0], events: vec!(Event::Input(7), Event::Write { addr: 0, value: 7 }) },
+            ExecRecord { ip: 2, opcode: 4, modes: [0, 0, 0], events: vec!(Event::Read { addr: 0, value: 7 }, Event::Output(7)) },
+            ExecRecord { ip: 4, opcode: 99, modes: [0, 0, 0], events: vec!() },
+        ]);
+        let mut vm = VM::new(vec!(3, 0, 4, 0, 99), vec!(7));
+        vm.run().unwrap();
+        assert!(vm.exec_log().is_empty());
+        let mut vm = VM::new(vec!(3, 0, 4, 0, 99), vec!()).with_exec_log(true);
+        assert_eq!(vm.run(), Ok(RunOutcome::NeedsInput));
+        assert!(vm.exec_log().is_empty());
+        vm.push_input(5);
+        vm.resume().unwrap();
+        assert_eq!(vm.exec_log().iter().map(|record| (record.ip, record.events.len())).collect::<Vec<_>>(),
+                   vec!((0, 2), (2, 2), (4, 0)));
+    }
+
+    #[test]
+    fn phases_must_be_distinct_and_in_range() {
+        assert_eq!(validate_phases(&[4, 3, 2, 1, 0], 0..=4), Ok(()));
+        assert_eq!(validate_phases(&[4, 3, 5, 1, 0], 0..=4), Err(VmError::PhaseOutOfRange(5)));
+        assert_eq!(validate_phases(&[4, 1, 2, 1, 0], 0..=4), Err(VmError::DuplicatePhase(1)));
+        assert_eq!(max_thruster_signal_feedback(&[99], &[0, 1, 2, 3, 4]), Err(VmError::PhaseOutOfRange(0)));
+    }
+
+    #[test]
+    fn jump_to_resumes_from_the_target() {
+        let mut vm = VM::new(vec!(104, 1, 104, 2, 99), vec!());
+        vm.jump_to(2).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.outputs(), &[2]);
+        assert_eq!(vm.jump_to(5), Err(VmError::JumpOutOfRange(5)));
+        assert_eq!(vm.ip, 4);
+    }
+
+    #[test]
+    fn static_opcode_counts_follow_the_linear_decode() {
+        let counts = static_opcode_counts(&[1101, 1, 1, 0, 104, 0, 104, 0, 99, 1, 1]);
+        assert_eq!(counts, [(1, 1), (4, 2), (99, 1)].iter().copied().collect::<HashMap<i32, usize>>());
+    }
+
+    #[test]
+    fn into_outputs_yields_until_halt_or_error() {
+        let outputs: Vec<_> = VM::new(vec!(104, 1, 104, 2, 99), vec!()).into_outputs().collect();
+        assert_eq!(outputs, vec!(Ok(1), Ok(2)));
+        let mut outputs = VM::new(vec!(104, 1, 42), vec!()).into_outputs();
+        assert_eq!(outputs.next(), Some(Ok(1)));
+        assert_eq!(outputs.next().unwrap().unwrap_err().root_cause(), &VmError::UnknownOpcode(42));
+        assert_eq!(outputs.next(), None);
+        // Taking three of five outputs runs the VM only as far as the third.
+        let produced = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = produced.clone();
+        let vm = VM::new(vec!(104, 1, 104, 2, 104, 3, 104, 4, 104, 5, 99), vec!())
+            .with_output_transform(Box::new(move |value| {
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                value
+            }));
+        let first: Vec<_> = vm.into_outputs().take(3).collect();
+        assert_eq!(first, vec!(Ok(1), Ok(2), Ok(3)));
+        assert_eq!(produced.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn strict_halt_rejects_mode_digits() {
+        assert_eq!(VM::new(vec!(1099), vec!()).run(), Ok(RunOutcome::Halted));
+        let mut vm = VM::new(vec!(1099), vec!()).with_strict_halt(true);
+        assert_eq!(vm.run().unwrap_err().root_cause(), &VmError::InvalidMode(1099));
+        assert_eq!(VM::new(vec!(99), vec!()).with_strict_halt(true).run(), Ok(RunOutcome::Halted));
+    }
+
+    #[test]
+    fn output_count_for_counts_outputs() {
+        assert_eq!(output_count_for(&[3, 0, 4, 0, 4, 0, 99], &[5], 100), Ok(2));
+        assert_eq!(output_count_for(&[99], &[], 100), Ok(0));
+    }
+
+    #[test]
+    fn relative_mode_is_detected() {
+        assert!(uses_relative_mode(&[109, 1, 99]));
+        assert!(uses_relative_mode(&[204, -1, 99]));
+        assert!(!uses_relative_mode(&[104, 1, 99]));
+    }
+
+    #[test]
+    fn jump_limit_stops_a_looping_program() {
+        let mut vm = VM::new(vec!(1105, 1, 0), vec!()).with_max_jumps(3);
+        assert_eq!(vm.run().unwrap_err().root_cause(), &VmError::JumpLimitExceeded);
+        assert_eq!(vm.jumps, 3);
+    }
+
+    #[test]
+    fn undo_and_redo_memory_writes() {
+        let mut vm = VM::new(vec!(1101, 1, 2, 5, 99, 0), vec!()).with_history(true);
+        vm.run().unwrap();
+        assert_eq!(vm.get_mem(5), Ok(3));
+        assert!(vm.undo());
+        assert_eq!((vm.get_mem(5), vm.ip), (Ok(0), 0));
+        assert!(!vm.undo());
+        assert!(vm.redo());
+        assert_eq!((vm.get_mem(5), vm.ip), (Ok(3), 4));
+        assert!(!vm.redo());
+        assert!(vm.undo());
+        assert!(!vm.is_halted());
+        assert_eq!(vm.run(), Ok(RunOutcome::Halted));
+        assert_eq!(vm.get_mem(5), Ok(3));
+    }
+
+    #[cfg(feature = "flate2")]
+    #[test]
+    fn gzip_programs_are_decompressed() {
+        use flate2::write::GzEncoder;
+
+        let path = std::env::temp_dir().join(format!("day7-gz-{}.txt.gz", std::process::id()));
+        let mut encoder = GzEncoder::new(File::create(&path).unwrap(), flate2::Compression::default());
+        encoder.write_all(b"3,0,4,0,99\n").unwrap();
+        encoder.finish().unwrap();
+        let program = read_program_gz(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(program.unwrap(), vec!(3, 0, 4, 0, 99));
+    }
+
+    #[test]
+    fn equivalence_is_checked_on_the_sample_inputs() {
+        let echo = [3, 0, 4, 0, 99];
+        let echo_elsewhere = [3, 5, 4, 5, 99, 0];
+        let constant = [3, 0, 104, 1, 99];
+        let inputs = [vec!(1), vec!(2)];
+        assert!(behaviorally_equivalent(&echo, &echo_elsewhere, &inputs, 100));
+        assert!(!behaviorally_equivalent(&echo, &constant, &inputs, 100));
+        assert!(behaviorally_equivalent(&echo, &constant, &[vec!(1)], 100));
+        assert!(!behaviorally_equivalent(&echo, &[42], &inputs, 100));
+    }
+
+    #[test]
+    fn input_cursor_can_be_rewound() {
+        let mut vm = VM::new(vec!(3, 0, 4, 0, 99), vec!(5, 6));
+        vm.run().unwrap();
+        assert_eq!(vm.input_cursor(), 1);
+        vm.reset(vec!(5, 6));
+        vm.set_input_cursor(1).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.outputs(), &[6]);
+        assert_eq!(vm.set_input_cursor(2), Ok(()));
+        assert_eq!(vm.set_input_cursor(3), Err(VmError::InputCursorOutOfRange(3)));
+    }
+
+    #[test]
+    fn phase_settings_are_ranked_by_signal() {
+        let program = [3, 15, 3, 16, 1002, 16, 10, 16, 1, 16, 15, 15, 4, 15, 99, 0, 0];
+        let ranked = rank_phase_settings(&program, &[0, 1, 2, 3, 4], false).unwrap();
+        assert_eq!(ranked.len(), 120);
+        assert_eq!(ranked[0], (vec!(4, 3, 2, 1, 0), 43210));
+        assert!(ranked.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+        assert_eq!(rank_phase_settings(&program, &[0, 1], true), Err(VmError::PhaseOutOfRange(0)));
+    }
+
+    #[test]
+    fn scalar_snapshots_restore_the_relative_base() {
+        let mut vm = VM::new(vec!(109, 5, 109, 5, 99), vec!());
+        vm.single_step().unwrap();
+        let snapshot = vm.full_scalar_snapshot();
+        assert_eq!((snapshot.ip, snapshot.relative_base), (2, 5));
+        vm.run().unwrap();
+        assert_eq!(vm.relative_base, 10);
+        vm.restore_scalars(snapshot.clone());
+        assert_eq!(vm.full_scalar_snapshot(), snapshot);
+        assert!(!vm.is_halted());
+    }
+
+    #[test]
+    fn output_before_input_is_interactive() {
+        assert!(is_interactive(&[104, 62, 3, 0, 99]));
+        assert!(!is_interactive(&[3, 0, 104, 1, 99]));
+        assert!(!is_interactive(&[99, 104, 62, 3, 0]));
+    }
+
+    #[test]
+    fn run_until_input_count_stops_after_the_nth_input() {
+        let mut vm = VM::new(vec!(3, 0, 3, 1, 3, 2, 99), vec!(7, 8, 9));
+        vm.run_until_input_count(2).unwrap();
+        assert_eq!((vm.input_cursor(), vm.ip), (2, 4));
+        assert_eq!((vm.get_mem(0), vm.get_mem(1)), (Ok(7), Ok(8)));
+        vm.run_until_input_count(5).unwrap();
+        assert!(vm.is_halted());
+    }
+
+    #[test]
+    fn opcode_at_step_looks_ahead() {
+        let program = [1101, 1, 1, 0, 104, 0, 99];
+        assert_eq!(opcode_at_step(&program, &[], 1), Ok(1));
+        assert_eq!(opcode_at_step(&program, &[], 2), Ok(4));
+        assert_eq!(opcode_at_step(&program, &[], 3), Ok(99));
+        assert_eq!(opcode_at_step(&program, &[], 10), Ok(99));
+        assert_eq!(opcode_at_step(&[3, 0, 104, 1, 99], &[], 5), Ok(3));
+    }
+
+    #[test]
+    fn static_instruction_count_stops_at_halt() {
+        assert_eq!(static_instruction_count(&[1101, 1, 1, 0, 104, 0, 99, 5, 5]), 3);
+        assert_eq!(static_instruction_count(&[]), 0);
+    }
+
+    #[test]
+    fn output_transform_rewrites_outputs() {
+        let mut vm = VM::new(vec!(104, 21, 104, -1, 99), vec!()).with_output_transform(Box::new(|value| value * 2));
+        vm.run().unwrap();
+        assert_eq!(vm.outputs(), &[42, -2]);
+    }
+
+    #[test]
+    fn clear_halt_only_after_a_clean_halt() {
+        let mut vm = VM::new(vec!(104, 1, 99), vec!());
+        vm.run().unwrap();
+        vm.clear_halt().unwrap();
+        vm.jump_to(0).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.outputs(), &[1, 1]);
+        let mut vm = VM::new(vec!(42), vec!());
+        assert!(vm.run().is_err());
+        assert_eq!(vm.clear_halt(), Err(VmError::HaltedOnError));
+        assert!(vm.is_halted());
+    }
+
+    #[test]
+    fn verbose_disassembly_lists_raw_words() {
+        assert_eq!(disassemble_verbose(&[1002, 4, 3, 4, 33, 99]), vec!(
+            "    0: [1002, 4, 3, 4]          MUL @4 #3 @4",
+            "    4: [33]                     DATA 33",
+            "    5: [99]                     HALT",
+        ));
+    }
+
+    #[test]
+    fn jump_targets_are_immediate_destinations() {
+        let targets = jump_targets(&[1105, 1, 7, 1106, 0, 9, 5, 0, 0, 99]);
+        assert_eq!(targets, [7, 9].iter().copied().collect::<HashSet<usize>>());
+    }
+
+    #[test]
+    fn taint_follows_input_to_output() {
+        let program = vec!(3, 11, 1001, 11, 1, 12, 4, 12, 104, 5, 99, 0, 0);
+        let mut vm = VM::new(program.clone(), vec!(7)).with_taint_tracking(true);
+        vm.run().unwrap();
+        assert_eq!(vm.outputs(), &[8, 5]);
+        assert_eq!(vm.tainted_outputs(), vec!(true, false));
+        let mut vm = VM::new(program, vec!(7));
+        vm.run().unwrap();
+        assert_eq!(vm.tainted_outputs(), vec!(false, false));
+    }
+
+    #[test]
+    fn steps_to_halt_counts_executed_instructions() {
+        assert_eq!(steps_to_halt(&[104, 1, 104, 2, 99], &[], 100), Ok(3));
+        assert_eq!(steps_to_halt(&[3, 0, 99], &[], 100), Err(VmError::InputExhausted));
+        assert_eq!(steps_to_halt(&[1105, 1, 0], &[], 100), Err(VmError::StepLimitExceeded));
+    }
+
+    #[test]
+    fn read_programs_skips_blank_and_comment_lines() {
+        let path = std::env::temp_dir().join(format!("day7-programs-{}.txt", std::process::id()));
+        std::fs::write(&path, "# two programs\n1,2\n\n 3, 4,5 \n").unwrap();
+        let programs = read_programs(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(programs.unwrap(), vec!(vec!(1, 2), vec!(3, 4, 5)));
+    }
+
+    #[test]
+    fn slowest_input_skips_failed_runs() {
+        // Nonzero input jumps to an extra output before halting.
+        let program = [3, 0, 1005, 0, 6, 99, 104, 1, 99];
+        assert_eq!(slowest_input(&program, &[vec!(0), vec!(1), vec!()], 100), Some((1, 4)));
+        assert_eq!(slowest_input(&program, &[vec!()], 100), None);
+    }
+
+    #[test]
+    fn extended_memory_use_is_detected() {
+        assert_eq!(requires_extended_memory(&[1101, 1, 1, 100, 99], &[], 100), Ok(true));
+        assert_eq!(requires_extended_memory(&[1101, 1, 1, 0, 99], &[], 100), Ok(false));
+    }
+
+    #[test]
+    fn bounded_output_channel_blocks_until_read() {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let mut vm = VM::new(vec!(104, 1, 104, 2, 104, 3, 99), vec!()).with_output_channel(sender);
+        let handle = thread::spawn(move || vm.run());
+        assert_eq!(receiver.iter().collect::<Vec<_>>(), vec!(1, 2, 3));
+        assert_eq!(handle.join().unwrap(), Ok(RunOutcome::Halted));
+        let (sender, receiver) = mpsc::sync_channel(1);
+        drop(receiver);
+        let mut vm = VM::new(vec!(104, 1, 99), vec!()).with_output_channel(sender);
+        assert_eq!(vm.run().unwrap_err().root_cause(), &VmError::OutputChannelClosed);
+    }
+
+    #[test]
+    fn exhausted_input_pauses_on_the_input_instruction() {
+        let mut vm = VM::new(vec!(3, 0, 4, 0, 99), vec!());
+        assert_eq!(vm.run(), Ok(RunOutcome::NeedsInput));
+        assert!(vm.waiting_for_input && !vm.is_halted());
+        assert_eq!((vm.ip, vm.steps), (0, 0));
+    }
+
+    #[test]
+    fn output_honors_parameter_modes() {
+        assert_eq!(run_program(vec!(104, 5, 4, 0, 99), vec!()), Ok(vec!(5, 104)));
+        assert_eq!(run_program(vec!(109, 4, 204, 1, 99, 42), vec!()), Ok(vec!(42)));
+    }
+
+    #[test]
+    fn untaken_jumps_skip_both_parameters() {
+        assert_eq!(run_program(vec!(1106, 1, 100, 104, 7, 99), vec!()), Ok(vec!(7)));
+        assert_eq!(run_program(vec!(1105, 0, 100, 104, 7, 99), vec!()), Ok(vec!(7)));
+    }
+
+    #[test]
+    fn relative_mode_reads_and_writes() {
+        // Input to [20], output it, then [21] = [20] + 1 and output that.
+        let program = vec!(109, 20, 203, 0, 204, 0, 21201, 0, 1, 1, 204, 1, 99);
+        let mut vm = VM::new(program, vec!(7));
+        vm.run().unwrap();
+        assert_eq!(vm.outputs(), &[7, 8]);
+        assert_eq!(vm.relative_base, 20);
+        assert_eq!(run_program(vec!(109, 1, 204, -1, 99), vec!()), Ok(vec!(109)));
+    }
+
+    #[test]
+    fn words_are_wide_enough_for_large_products() {
+        assert_eq!(run_program(vec!(1102, 34915192, 34915192, 7, 4, 7, 99, 0), vec!()), Ok(vec!(1219070632396864)));
+        assert_eq!(run_program(vec!(104, 1125899906842624, 99), vec!()), Ok(vec!(1125899906842624)));
+    }
+
+    #[test]
+    fn parse_errors_name_the_bad_token() {
+        let error = parse_line(3, "1, 2, x, 4").unwrap_err();
+        assert!(error.to_string().contains("\"x\" at line 3, position 2"), "{}", error);
+        assert!(matches!(error, ParseProgramError::InvalidToken { line: 3, position: 2, .. }));
+        assert_eq!(parse_line(1, " 1 , 2 ").unwrap(), vec!(1, 2));
+    }
+
+    #[test]
+    fn memory_grows_on_demand() {
+        let mut vm = VM::new(vec!(0; 10), vec!());
+        assert_eq!(vm.read_mem(10_000), Ok(0));
+        vm.write_mem(5000, 77).unwrap();
+        assert_eq!(vm.read_mem(5000), Ok(77));
+        assert_eq!(vm.read_mem(MAX_MEMORY as i64), Err(VmError::AddressOutOfRange(MAX_MEMORY as i64)));
+        let mut vm = VM::new(vec!(0; 10), vec!()).with_memory_growth(false);
+        assert_eq!(vm.read_mem(10), Err(VmError::AddressOutOfRange(10)));
+    }
+
+    #[test]
+    fn single_step_reports_halting() {
+        let mut vm = VM::new(vec!(104, 1, 104, 2, 99), vec!());
+        assert_eq!(vm.single_step(), Ok(false));
+        assert_eq!(vm.outputs(), &[1]);
+        assert_eq!(vm.single_step(), Ok(false));
+        assert_eq!(vm.single_step(), Ok(true));
+        assert_eq!(vm.single_step(), Ok(true));
+        assert_eq!(vm.steps, 3);
+    }
+
+    #[test]
+    fn run_until_output_stops_at_each_output() {
+        let mut vm = VM::new(vec!(104, 1, 104, 2, 99), vec!());
+        assert_eq!(vm.run_until_output(), Ok(OutputEvent::Output(1)));
+        assert_eq!(vm.run_until_output(), Ok(OutputEvent::Output(2)));
+        assert_eq!(vm.run_until_output(), Ok(OutputEvent::Halted));
+        let mut vm = VM::new(vec!(3, 0, 4, 0, 99), vec!());
+        assert_eq!(vm.run_until_output(), Ok(OutputEvent::NeedsInput));
+        vm.push_input(5);
+        assert_eq!(vm.run_until_output(), Ok(OutputEvent::Output(5)));
+    }
+
+    #[test]
+    fn each_instruction_sets_the_next_ip() {
+        // (program, ip after one step)
+        let cases = [
+            (vec!(1101, 1, 1, 0, 99), 4),
+            (vec!(104, 1, 99), 2),
+            (vec!(1105, 1, 5, 99, 99, 99), 5),
+            (vec!(1105, 0, 5, 99, 99, 99), 3),
+            (vec!(1106, 0, 4, 99, 99), 4),
+            (vec!(1107, 1, 2, 0, 99), 4),
+            (vec!(109, 1, 99), 2),
+            (vec!(99), 0),
+        ];
+        for (program, next_ip) in cases.iter() {
+            let mut vm = VM::new(program.clone(), vec!());
+            vm.single_step().unwrap();
+            assert_eq!(vm.ip, *next_ip, "{:?}", program);
+        }
+    }
+
+    #[test]
+    fn library_solves_both_parts_of_the_examples() {
+        let chain = [3, 15, 3, 16, 1002, 16, 10, 16, 1, 16, 15, 15, 4, 15, 99, 0, 0];
+        let feedback = [3, 26, 1001, 26, -4, 26, 3, 27, 1002, 27, 2, 27, 1, 27, 26, 27, 4, 27, 1001, 28, -1, 28,
+                        1005, 28, 6, 99, 0, 0, 5];
+        assert_eq!(max_thruster_signal(&chain, &[0, 1, 2, 3, 4]), Ok(43210));
+        assert_eq!(max_thruster_signal_feedback(&feedback, &[5, 6, 7, 8, 9]), Ok(139629729));
+        assert_eq!(answer_line(1, 43210), "Part 1: 43210");
+    }
+
+    #[test]
+    fn permutations_cover_every_ordering_once() {
+        let all = permutations(&[1, 2, 3]);
+        assert_eq!(all.len(), 6);
+        assert_eq!(all.iter().collect::<HashSet<_>>().len(), 6);
+        assert_eq!(permutations(&[5, 6, 7, 8, 9]).into_iter().collect::<HashSet<_>>().len(), 120);
+        assert_eq!(permutations(&[]), vec!(Vec::<i64>::new()));
+    }
+
+    #[test]
+    fn amplifier_count_follows_the_phases() {
+        let program = [3, 15, 3, 16, 1002, 16, 10, 16, 1, 16, 15, 15, 4, 15, 99, 0, 0];
+        assert_eq!(max_thruster_signal(&program, &[0, 1, 2]), Ok(210));
+        assert_eq!(max_thruster_signal(&program, &[0]), Ok(0));
+        assert_eq!(test_amps(program.to_vec(), vec!(2, 1, 0)), Ok(210));
+        assert_eq!(test_amps(program.to_vec(), vec!(0, 0, 1)), Err(VmError::DuplicatePhase(0)));
+    }
+
+    #[test]
+    fn memory_can_be_patched_before_running() {
+        let mut vm = VM::new(vec!(1, 0, 0, 0, 99), vec!());
+        vm.set_mem(1, 4).unwrap();
+        vm.set_mem(2, 4).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.get_mem(0), Ok(198));
+        assert_eq!(vm.get_mem(100), Ok(0));
+        assert!(vm.set_mem(MAX_MEMORY, 1).is_err());
+    }
+
+    #[test]
+    fn parse_program_builds_programs_from_strings() {
+        assert_eq!(parse_program("3,0,4,0,99\n").unwrap(), vec!(3, 0, 4, 0, 99));
+        assert_eq!(parse_program(" 1101, 100, -1, 4, 0 ").unwrap(), vec!(1101, 100, -1, 4, 0));
+        match parse_program("1,2,") {
+            Err(ParseProgramError::InvalidToken { position: 2, token, .. }) => assert_eq!(token, ""),
+            other => panic!("expected an InvalidToken error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn step_limit_stops_an_endless_loop() {
+        let mut vm = VM::new(vec!(1106, 0, 0, 99), vec!()).with_max_steps(1000);
+        assert_eq!(vm.run(), Ok(RunOutcome::StepLimit));
+        assert_eq!(vm.steps, 1000);
+        let mut vm = VM::new(vec!(104, 1, 99), vec!()).with_max_steps(2);
+        assert_eq!(vm.run(), Ok(RunOutcome::Halted));
+    }
+
+    #[test]
+    fn outputs_can_be_taken() {
+        let mut vm = VM::new(vec!(104, 1, 104, 2, 104, 3, 3, 0, 4, 0, 99), vec!());
+        vm.run().unwrap();
+        assert_eq!(vm.last_output(), Some(3));
+        assert_eq!(vm.take_outputs(), vec!(1, 2, 3));
+        assert_eq!(vm.last_output(), None);
+        vm.push_input(9);
+        vm.resume().unwrap();
+        assert_eq!(vm.take_outputs(), vec!(9));
+    }
+
+    #[test]
+    fn disassembler_renders_mnemonics() {
+        assert_eq!(disassemble(&[1002, 4, 3, 4, 33]), "    0: MUL @4 #3 @4\n    4: DATA 33");
+        assert_eq!(disassemble(&[109, 1, 204, -1, 99]), "    0: ADJ #1\n    2: OUT ~-1\n    4: HALT");
+    }
+
+    #[test]
+    fn clones_run_independently() {
+        let mut vm = VM::new(vec!(3, 0, 1002, 0, 2, 0, 4, 0, 99), vec!());
+        vm.run().unwrap();
+        let mut copy = vm.clone();
+        copy.push_input(21);
+        copy.resume().unwrap();
+        assert_eq!(copy.outputs(), &[42]);
+        assert!(copy.is_halted());
+        assert!(!vm.is_halted() && vm.waiting_for_input);
+        assert_eq!(vm.program, vec!(3, 0, 1002, 0, 2, 0, 4, 0, 99));
+    }
+
+    #[test]
+    fn reset_restores_the_loaded_program() {
+        let program = vec!(3, 0, 1002, 0, 2, 0, 4, 0, 99);
+        let mut vm = VM::new(program.clone(), vec!(5)).with_max_steps(10);
+        vm.run().unwrap();
+        assert_ne!(vm.program, program);
+        vm.reset(vec!(6));
+        assert_eq!(vm.program, program);
+        assert_eq!((vm.ip, vm.steps, vm.outputs().len()), (0, 0, 0));
+        assert_eq!(vm.max_steps, Some(10));
+        vm.run().unwrap();
+        assert_eq!(vm.outputs(), &[12]);
+    }
+
+    #[test]
+    fn io_pointers_count_inputs_and_outputs() {
+        let mut vm = VM::new(vec!(3, 0, 3, 0, 4, 0, 99), vec!(1, 2));
+        vm.single_step().unwrap();
+        assert_eq!(vm.input_cursor(), 1);
+        vm.run().unwrap();
+        let snapshot = vm.full_scalar_snapshot();
+        assert_eq!((snapshot.in_p, snapshot.out_p), (2, 1));
+    }
+
+    #[test]
+    fn input_callback_supplies_missing_inputs() {
+        let mut next = vec!(30, 20, 10);
+        let mut vm = VM::new(vec!(3, 0, 4, 0, 3, 0, 4, 0, 3, 0, 4, 0, 99), vec!(5))
+            .with_input_callback(Box::new(move || next.pop().unwrap()));
+        vm.run().unwrap();
+        assert_eq!(vm.outputs(), &[5, 10, 20]);
+        assert_eq!(vm.inputs, vec!(5, 10, 20));
+    }
+
+    #[test]
+    fn ascii_outputs_render_as_text() {
+        let mut vm = VM::new(vec!(104, 72, 104, 73, 104, 10, 104, 1000, 99), vec!());
+        vm.run().unwrap();
+        assert_eq!(vm.outputs_as_string(), "HI\n");
+        assert_eq!(vm.non_ascii_outputs(), vec!(1000));
+        let mut vm = VM::new(vec!(3, 0, 4, 0, 99), vec!());
+        vm.ascii_inputs("A");
+        vm.run().unwrap();
+        assert_eq!(vm.outputs_as_string(), "A");
+    }
+
+    #[test]
+    fn parameters_are_checked_before_executing() {
+        let mut vm = VM::new(vec!(704, 0, 99), vec!());
+        assert_eq!(vm.run().unwrap_err().root_cause(), &VmError::BadMode(7));
+        let mut vm = VM::new(vec!(1, 0, 0, -2, 99), vec!()).with_exec_log(true);
+        assert_eq!(vm.run().unwrap_err().root_cause(), &VmError::NegativeAddress(-2));
+        assert!(vm.exec_log().is_empty());
+    }
+
+    #[test]
+    fn stats_count_executions_per_opcode() {
+        let mut vm = VM::new(vec!(104, 1, 1101, 1, 1, 0, 104, 2, 99), vec!());
+        vm.run().unwrap();
+        assert_eq!(vm.stats()[&4], 2);
+        assert_eq!(vm.stats()[&1], 1);
+        assert_eq!(vm.stats_summary(), "ADD  1\nOUT  2\nHALT 1");
+    }
+
+    #[test]
+    fn breakpoints_stop_before_the_instruction() {
+        let mut vm = VM::new(vec!(104, 1, 104, 2, 104, 3, 99), vec!());
+        vm.add_breakpoint(2);
+        vm.add_breakpoint(0);
+        assert_eq!(vm.run_to_breakpoint(), Ok(true));
+        assert_eq!(vm.ip, 0);
+        assert_eq!(vm.run_to_breakpoint(), Ok(true));
+        assert_eq!((vm.ip, vm.outputs()), (2, &[1][..]));
+        assert!(vm.remove_breakpoint(0));
+        assert_eq!(vm.run_to_breakpoint(), Ok(false));
+        assert!(vm.is_halted());
+        // Stepping off a breakpoint does not skip the next one.
+        let mut vm = VM::new(vec!(104, 1, 104, 2, 104, 3, 99), vec!());
+        vm.add_breakpoint(0);
+        vm.add_breakpoint(2);
+        assert_eq!(vm.run_to_breakpoint(), Ok(true));
+        vm.single_step().unwrap();
+        assert_eq!(vm.run_to_breakpoint(), Ok(true));
+        assert_eq!((vm.ip, vm.outputs()), (2, &[1][..]));
+    }
+
+    // A trace writer the test can still read after handing it to the VM.
+    struct SharedBuf(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn trace_goes_to_the_writer() {
+        let buf = std::sync::Arc::new(std::sync::Mutex::new(vec!()));
+        let mut vm = VM::new(vec!(1101, 2, 3, 5, 104, 0, 99), vec!()).with_trace_writer(Box::new(SharedBuf(buf.clone())));
+        vm.run().unwrap();
+        let text = String::from_utf8(buf.lock().unwrap().clone()).unwrap();
+        assert!(text.contains("I_ADD [5] <- =2 + =3"), "{}", text);
+        assert!(text.contains("I_OUTPUT =5"), "{}", text);
+        assert!(text.contains("I_HALT"), "{}", text);
+    }
+
+    #[test]
+    fn arithmetic_overflow_is_an_error() {
+        let mut vm = VM::new(vec!(1102, i64::MAX, 2, 0, 99), vec!());
+        assert_eq!(vm.run().unwrap_err().root_cause(), &VmError::ArithmeticOverflow { opcode: 2, a: i64::MAX, b: 2 });
+        let mut vm = VM::new(vec!(1101, i64::MAX, 1, 0, 99), vec!());
+        assert_eq!(vm.run().unwrap_err().root_cause(), &VmError::ArithmeticOverflow { opcode: 1, a: i64::MAX, b: 1 });
+    }
+
+    #[test]
+    fn noun_verb_search_finds_the_first_match() {
+        assert_eq!(find_noun_verb(&[1, 0, 0, 0, 99], 198), Some((4, 4)));
+        assert_eq!(find_noun_verb(&[1, 0, 0, 0, 99], 2), Some((0, 0)));
+        assert_eq!(find_noun_verb(&[1, 0, 0, 0, 99], -1), None);
+        assert_eq!(find_noun_verb(&[1, 0], 2), None);
+    }
+
+    #[test]
+    fn threaded_solver_agrees_with_the_sequential_one() {
+        let feedback = [3, 26, 1001, 26, -4, 26, 3, 27, 1002, 27, 2, 27, 1, 27, 26, 27, 4, 27, 1001, 28, -1, 28,
+                        1005, 28, 6, 99, 0, 0, 5];
+        assert_eq!(max_thruster_signal_feedback_threaded(&feedback, &[5, 6, 7, 8, 9]), Ok(139629729));
+        assert_eq!(max_thruster_signal_feedback(&feedback, &[5, 6, 7, 8, 9]), Ok(139629729));
+        assert_eq!(test_amps_threaded(&feedback, &[9, 8, 7, 6, 5]), Ok(139629729));
+        assert_eq!(test_amps_threaded(&[42], &[5, 6]).unwrap_err().root_cause(), &VmError::UnknownOpcode(42));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn saved_state_resumes_where_it_stopped() {
+        let program = vec!(3, 21, 1008, 21, 8, 20, 1005, 20, 22, 107, 8, 21, 20, 1006, 20, 31, 1106, 0, 36, 98, 0, 0,
+                           1002, 21, 125, 20, 4, 20, 1105, 1, 46, 104, 999, 1105, 1, 46, 1101, 1000, 1, 20, 4, 20,
+                           1105, 1, 46, 98, 99);
+        let mut vm = VM::new(program, vec!(9)).with_max_steps(4);
+        assert_eq!(vm.run(), Ok(RunOutcome::StepLimit));
+        let mut restored = VM::load_state(&vm.save_state()).unwrap();
+        assert_eq!((restored.ip, restored.steps), (vm.ip, 4));
+        restored.max_steps = None;
+        assert_eq!(restored.run(), Ok(RunOutcome::Halted));
+        assert_eq!(restored.outputs(), &[1001]);
+    }
+
+    #[test]
+    fn instructions_display_as_mnemonics() {
+        let names: Vec<String> = [1, 2, 3, 4, 5, 6, 7, 8, 9, 99].iter()
+            .map(|&opcode| instruction_for(opcode).unwrap().to_string())
+            .collect();
+        assert_eq!(names, ["ADD", "MUL", "IN", "OUT", "JT", "JF", "LT", "EQ", "ADJ", "HALT"]);
+        assert_eq!(Instruction { opcode: 42, steps_next: 1 }.to_string(), "UNKNOWN(42)");
+    }
+
+    #[test]
+    fn builder_sets_options_new_does_not_take() {
+        let buf = std::sync::Arc::new(std::sync::Mutex::new(vec!()));
+        let mut vm = VmBuilder::new().program(vec!(1105, 1, 0)).verbose(true).max_steps(10)
+            .trace_writer(Box::new(SharedBuf(buf.clone()))).build();
+        assert!(vm.verbose);
+        assert_eq!(vm.max_steps, Some(10));
+        assert_eq!(vm.run(), Ok(RunOutcome::StepLimit));
+        assert_eq!(vm.steps, 10);
+        let text = String::from_utf8(buf.lock().unwrap().clone()).unwrap();
+        assert_eq!(text.matches("I_JT").count(), 10, "{}", text);
+        let mut vm = VmBuilder::new().program(vec!(204, 0, 99, 42)).relative_base(3).inputs(vec!(1)).build();
+        vm.run().unwrap();
+        assert_eq!(vm.outputs(), &[42]);
+        assert_eq!(vm.pending_inputs(), &[1]);
+    }
+
+    #[test]
+    fn pending_inputs_can_be_peeked() {
+        let mut vm = VM::new(vec!(3, 0, 104, 1, 99), vec!());
+        vm.push_input(4);
+        vm.push_input(5);
+        assert_eq!(vm.peek_input(), Some(4));
+        vm.run().unwrap();
+        assert_eq!(vm.peek_input(), Some(5));
+        assert_eq!(vm.pending_inputs(), &[5]);
+        assert_eq!(vm.output_count(), 1);
+    }
+
+    #[test]
+    fn programs_may_span_lines() {
+        assert_eq!(read_program_from(&b"1,2,\r\n3,4\r\n\r\n"[..]).unwrap(), vec!(1, 2, 3, 4));
+        assert_eq!(read_program_from(&b"  1,2\n3 , 4\n"[..]).unwrap(), vec!(1, 2, 3, 4));
+        assert!(parse_program("1,x").is_err());
+        let error = parse_program("1,2,3\n4,5,6\n\n7,x,9").unwrap_err();
+        assert!(matches!(error, ParseProgramError::InvalidToken { line: 4, position: 1, .. }), "{}", error);
+    }
+
+    #[test]
+    fn narrow_and_wide_words_agree() {
+        let program = [3, 9, 8, 9, 10, 9, 4, 9, 99, -1, 8];
+        for &input in [7, 8, 9].iter() {
+            let mut narrow = Vm32::from_words(program.iter().map(|&word| word as i32).collect(), vec!(input as i32));
+            let mut wide = Vm64::from_words(program.to_vec(), vec!(input));
+            narrow.run().unwrap();
+            wide.run().unwrap();
+            assert_eq!(narrow.outputs().iter().map(|&word| word as i64).collect::<Vec<_>>(), wide.outputs());
+        }
+        let mut narrow = Vm32::from_words(vec!(1002, 5, 3, 5, 99, 1_000_000_000), vec!());
+        assert!(matches!(narrow.run().unwrap_err().root_cause(), VmError::ArithmeticOverflow { opcode: 2, .. }));
+        assert!(Vm64::from_words(vec!(1002, 5, 3, 5, 99, 1_000_000_000), vec!()).run().is_ok());
+    }
+
+    #[test]
+    fn run_program_returns_outputs() {
+        assert_eq!(run_program(vec!(3, 0, 4, 0, 99), vec!(42)), Ok(vec!(42)));
+        assert_eq!(run_program(vec!(3, 0, 4, 0, 99), vec!()), Err(VmError::InputExhausted));
+        assert_eq!(run_program(vec!(42), vec!()).unwrap_err().root_cause(), &VmError::UnknownOpcode(42));
+    }
+
+    #[test]
+    fn revisiting_an_ip_without_progress_is_a_loop() {
+        let mut vm = VM::new(vec!(1101, 1, 1, 9, 1105, 1, 4, 99, 0, 0), vec!()).with_loop_detection(100);
+        assert_eq!(vm.run(), Err(VmError::InfiniteLoop { ip: 4 }));
+        // A counting loop writes memory on every pass, so it is not flagged.
+        let mut vm = VM::new(vec!(1001, 12, 1, 12, 1007, 12, 500, 13, 1005, 13, 0, 99, 0, 0), vec!())
+            .with_loop_detection(3);
+        vm.run().unwrap();
+        assert_eq!(vm.get_mem(12), Ok(500));
+    }
+
+    #[test]
+    fn immediate_destinations_are_rejected() {
+        let mut vm = VM::new(vec!(10001, 0, 0, 0, 99), vec!());
+        assert_eq!(vm.run().unwrap_err().root_cause(), &VmError::BadMode(1));
+        let mut vm = VM::new(vec!(103, 0, 99), vec!(5));
+        assert_eq!(vm.run().unwrap_err().root_cause(), &VmError::BadMode(1));
+        assert_eq!(run_program(vec!(1, 0, 0, 0, 4, 0, 99), vec!()), Ok(vec!(2)));
+    }
+
+    #[test]
+    fn run_reports_why_it_stopped() {
+        assert_eq!(VM::new(vec!(99), vec!()).run(), Ok(RunOutcome::Halted));
+        let mut vm = VM::new(vec!(3, 0, 99), vec!());
+        assert_eq!(vm.run(), Ok(RunOutcome::NeedsInput));
+        assert!(!vm.is_halted());
+        assert_eq!(VM::new(vec!(1105, 1, 0), vec!()).with_max_steps(10).run(), Ok(RunOutcome::StepLimit));
+    }
+
+    #[test]
+    fn permutations_come_in_lexicographic_order() {
+        let all: Vec<Vec<i64>> = Permutations::new(&[0, 1, 2, 3]).collect();
+        assert_eq!(all.len(), 24);
+        let mut sorted = all.clone();
+        sorted.sort();
+        assert_eq!(all, sorted);
+        assert_eq!(all[0], vec!(0, 1, 2, 3));
+        assert_eq!(all[23], vec!(3, 2, 1, 0));
+        assert_eq!(Permutations::new(&[]).count(), 1);
+    }
+
+    #[test]
+    fn mem_diff_lists_changed_words() {
+        let program = vec!(1, 5, 6, 0, 99, 10, 20);
+        let mut vm = VM::new(program.clone(), vec!());
+        vm.run().unwrap();
+        assert_eq!(mem_diff(&program, &vm.program), vec!((0, 1, 30)));
+        assert_eq!(mem_diff(&[1], &[1, 0, 5]), vec!((2, 0, 5)));
+        assert_eq!(mem_diff(&[1, 7], &[1]), vec!((1, 7, 0)));
+    }
+
+    #[test]
+    fn trace_operands_show_their_mode() {
+        let buf = std::sync::Arc::new(std::sync::Mutex::new(vec!()));
+        let mut vm = VmBuilder::new().program(vec!(1101, 2, 3, 9, 204, 3, 4, 9, 99, 0)).relative_base(6)
+            .trace_writer(Box::new(SharedBuf(buf.clone()))).build();
+        vm.run().unwrap();
+        let text = String::from_utf8(buf.lock().unwrap().clone()).unwrap();
+        assert!(text.contains("I_ADD [9] <- =2 + =3"), "{}", text);
+        assert!(text.contains("I_OUTPUT ~3=5"), "{}", text);
+        assert!(text.contains("I_OUTPUT [9]=5"), "{}", text);
+    }
+
+    #[test]
+    fn outputs_iter_yields_until_the_vm_stops() {
+        let mut vm = VM::new(vec!(104, 1, 104, 2, 104, 3, 99), vec!());
+        assert_eq!(vm.outputs_iter().collect::<Vec<_>>(), vec!(1, 2, 3));
+        assert!(vm.is_halted());
+        let mut vm = VM::new(vec!(104, 1, 3, 0, 104, 2, 99), vec!());
+        assert_eq!(vm.outputs_iter().collect::<Vec<_>>(), vec!(1));
+        vm.push_input(0);
+        assert_eq!(vm.outputs_iter().collect::<Vec<_>>(), vec!(2));
+    }
+
+    #[test]
+    fn current_instruction_matches_the_disassembler() {
+        let mut vm = VM::new(vec!(1002, 4, 3, 4, 33, 99, 77), vec!());
+        assert_eq!(vm.current_instruction(), Ok((String::from("MUL @4 #3 @4"), 4)));
+        vm.jump_to(5).unwrap();
+        assert_eq!(vm.current_instruction(), Ok((String::from("HALT"), 1)));
+        vm.jump_to(6).unwrap();
+        assert_eq!(vm.current_instruction(), Ok((String::from("DATA 77"), 1)));
+        assert_eq!(vm.ip, 6);
+        assert!(disassemble(&[1002, 4, 3, 4, 33, 99]).contains("MUL @4 #3 @4"));
+    }
+
+    #[test]
+    fn debug_session_runs_commands() {
+        assert_eq!(parse_debug_command(" break 4 "), Some(DebugCommand::Break(4)));
+        assert_eq!(parse_debug_command("break"), None);
+        assert_eq!(parse_debug_command("step 2"), None);
+        let mut vm = VM::new(vec!(104, 7, 1101, 1, 2, 9, 99, 0, 0, 0), vec!());
+        let mut out = vec!();
+        debug_session(&mut vm, &b"step\nbreak 6\ncontinue\nmem 9\nregs\nbogus\nquit\nstep\n"[..], &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "ip=0 next: OUT #7\noutput 7\nip=2 next: ADD #1 #2 @9\n\
+            breakpoint at 6\nip=6 next: HALT\n[9] = 3\nip=6 relative_base=0 in_p=0 out_p=1 steps=2\n\
+            unknown command: bogus\n");
+    }
+
+    #[test]
+    fn reused_amplifiers_give_the_same_answers() {
+        let program = [3, 15, 3, 16, 1002, 16, 10, 16, 1, 16, 15, 15, 4, 15, 99, 0, 0];
+        let mut amps = amplifiers(&program, 5);
+        assert_eq!(chain_signal(&mut amps, &[4, 3, 2, 1, 0]), Ok(43210));
+        assert_eq!(chain_signal(&mut amps, &[4, 3, 2, 1, 0]), Ok(43210));
+        assert_eq!(amps[0].pristine, program);
+        // Each amplifier runs IN, IN, MUL, ADD, OUT, HALT: 30 steps per ordering, 3600 for the search.
+        let mut total_steps = 0;
+        let mut best = i64::MIN;
+        for perm in Permutations::new(&[0, 1, 2, 3, 4]) {
+            best = best.max(chain_signal(&mut amps, &perm).unwrap());
+            total_steps += amps.iter().map(|amp| amp.steps).sum::<u64>();
+        }
+        assert_eq!((best, total_steps), (43210, 3600));
+        assert_eq!(max_thruster_signal(&program, &[0, 1, 1, 3, 4]), Err(VmError::DuplicatePhase(1)));
+    }
+
+    #[test]
+    fn answers_are_labelled_by_part() {
+        assert_eq!(answer_line(1, 79723), "Part 1: 79723");
+        assert_eq!(answer_line(2, 70602018), "Part 2: 70602018");
+    }
+}